@@ -0,0 +1,102 @@
+use rnix::{SyntaxElement, SyntaxKind, SyntaxNode};
+
+// Comments documenting a binding, without the comment markers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comments {
+    // Comment lines directly above the binding, joined with newlines
+    pub leading: Option<String>,
+    // Comment on the same line, after the binding
+    pub trailing: Option<String>,
+}
+
+// Positions among the parent's children of the first leading comment, the binding
+// itself and the trailing comment. Without comments the binding's index is used.
+pub(crate) fn attached(node: &SyntaxNode) -> Option<(usize, usize, usize)> {
+    let parent = node.parent()?;
+    let children = parent.children_with_tokens().collect::<Vec<_>>();
+    let index = children.iter().position(|x| x.as_node() == Some(node))?;
+
+    let mut first = index;
+    // A comment belongs to the binding below it if only a single newline separates
+    // them and it starts its own line
+    while first >= 3
+        && iswhitespace(&children[first - 1], |s| s.matches('\n').count() == 1)
+        && iscomment(&children[first - 2])
+        && iswhitespace(&children[first - 3], |s| s.contains('\n'))
+    {
+        first -= 2;
+    }
+
+    let mut last = index;
+    if let Some(x) = children.get(index + 1) {
+        if iscomment(x) {
+            last = index + 1;
+        } else if iswhitespace(x, |s| !s.contains('\n'))
+            && children.get(index + 2).map(iscomment) == Some(true)
+        {
+            last = index + 2;
+        }
+    }
+    Some((first, index, last))
+}
+
+pub fn getcomments(node: &SyntaxNode) -> Comments {
+    let (first, index, last) = match attached(node) {
+        Some(x) => x,
+        None => return Comments::default(),
+    };
+    let children = match node.parent() {
+        Some(x) => x.children_with_tokens().collect::<Vec<_>>(),
+        None => return Comments::default(),
+    };
+    let leading = if first < index {
+        let lines = children[first..index]
+            .iter()
+            .filter(|x| iscomment(x))
+            .map(|x| strip(&x.to_string()))
+            .collect::<Vec<_>>();
+        Some(lines.join("\n"))
+    } else {
+        None
+    };
+    let trailing = if last > index {
+        Some(strip(&children[last].to_string()))
+    } else {
+        None
+    };
+    Comments { leading, trailing }
+}
+
+// Render `text` as line comments, one per line, indented to match the binding
+pub(crate) fn render(text: &str, indent: &str) -> String {
+    text.lines()
+        .map(|l| {
+            if l.trim().is_empty() {
+                "#".to_string()
+            } else {
+                format!("# {}", l.trim_end())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(&format!("\n{}", indent))
+}
+
+fn strip(comment: &str) -> String {
+    if let Some(x) = comment.strip_prefix("/*") {
+        x.strip_suffix("*/").unwrap_or(x).trim().to_string()
+    } else {
+        let x = comment.trim_start_matches('#');
+        x.strip_prefix(' ').unwrap_or(x).trim_end().to_string()
+    }
+}
+
+fn iscomment(x: &SyntaxElement) -> bool {
+    x.kind() == SyntaxKind::TOKEN_COMMENT
+}
+
+fn iswhitespace(x: &SyntaxElement, f: impl Fn(&str) -> bool) -> bool {
+    match x.as_token() {
+        Some(t) => t.kind() == SyntaxKind::TOKEN_WHITESPACE && f(t.text()),
+        None => false,
+    }
+}
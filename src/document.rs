@@ -0,0 +1,201 @@
+use std::fmt;
+
+use rnix::SyntaxNode;
+
+use crate::{
+    errors::Error,
+    parse::{getcfgbase, parsechecked, ParseError},
+    value::NixValue,
+    wrapper::Wrapper,
+    write::{
+        addtoarr_node, addwrapper_node, deref_node, ensurearr_node, insertarr_node, replacearr_node, rmarr_node, rmwrapper_node,
+        setcomment_node, write_inner_node, write_node, Position,
+    },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Set { attr: String, value: String },
+    // Set the value inside any `mkForce`, `mkIf`, ... wrappers, keeping them
+    SetInner { attr: String, value: String },
+    Unset { attr: String },
+    ListAdd { attr: String, items: Vec<String> },
+    ListInsert { attr: String, items: Vec<String>, position: Position },
+    // Insert only the items that are not in the list yet
+    ListEnsure { attr: String, items: Vec<String>, position: Position },
+    // Fails if any item is not in the list when `strict` is set
+    ListRemove { attr: String, items: Vec<String>, strict: bool },
+    // Replace an element where it is, keeping the comments around it
+    ListReplace { attr: String, old: String, new: String },
+    Comment { attr: String, comment: Option<String> },
+    Wrap { attr: String, wrapper: Wrapper },
+    Unwrap { attr: String },
+}
+
+impl Operation {
+    pub fn attr(&self) -> &str {
+        match self {
+            Operation::Set { attr, .. }
+            | Operation::SetInner { attr, .. }
+            | Operation::Unset { attr }
+            | Operation::ListAdd { attr, .. }
+            | Operation::ListInsert { attr, .. }
+            | Operation::ListEnsure { attr, .. }
+            | Operation::ListRemove { attr, .. }
+            | Operation::ListReplace { attr, .. }
+            | Operation::Comment { attr, .. }
+            | Operation::Wrap { attr, .. }
+            | Operation::Unwrap { attr } => attr,
+        }
+    }
+
+    // The same operation on another attribute
+    pub(crate) fn withattr(&self, name: &str) -> Operation {
+        let mut op = self.clone();
+        match &mut op {
+            Operation::Set { attr, .. }
+            | Operation::SetInner { attr, .. }
+            | Operation::Unset { attr }
+            | Operation::ListAdd { attr, .. }
+            | Operation::ListInsert { attr, .. }
+            | Operation::ListEnsure { attr, .. }
+            | Operation::ListRemove { attr, .. }
+            | Operation::ListReplace { attr, .. }
+            | Operation::Comment { attr, .. }
+            | Operation::Wrap { attr, .. }
+            | Operation::Unwrap { attr } => *attr = name.to_string(),
+        }
+        op
+    }
+}
+
+// A configuration file that is parsed once and edited in memory.
+// Serialize it with `to_string()` once all changes are applied.
+#[derive(Clone, Debug)]
+pub struct Document {
+    root: SyntaxNode,
+}
+
+impl Document {
+    pub fn parse(f: &str) -> Result<Self, Error> {
+        let root = parsechecked(f)?;
+        if getcfgbase(&root).is_none() {
+            return Err(ParseError::default().into());
+        }
+        Ok(Document { root })
+    }
+
+    pub fn node(&self) -> &SyntaxNode {
+        &self.root
+    }
+
+    // Apply all operations in order. If any of them fails the document is left unchanged.
+    pub fn apply(&mut self, ops: &[Operation]) -> Result<(), Error> {
+        let mut root = self.root.clone();
+        for op in ops {
+            root = match op {
+                Operation::Set { attr, value } => write_node(&root, attr, value)?,
+                Operation::SetInner { attr, value } => write_inner_node(&root, attr, value)?,
+                Operation::Unset { attr } => deref_node(&root, attr)?,
+                Operation::ListAdd { attr, items } => addtoarr_node(&root, attr, items.clone())?,
+                Operation::ListInsert { attr, items, position } => {
+                    insertarr_node(&root, attr, items.clone(), position)?
+                }
+                Operation::ListEnsure { attr, items, position } => {
+                    ensurearr_node(&root, attr, items.clone(), position)?.0
+                }
+                Operation::ListRemove { attr, items, strict } => {
+                    rmarr_node(&root, attr, items.clone(), *strict)?.0
+                }
+                Operation::ListReplace { attr, old, new } => replacearr_node(&root, attr, old, new)?,
+                Operation::Comment { attr, comment } => {
+                    setcomment_node(&root, attr, comment.as_deref())?
+                }
+                Operation::Wrap { attr, wrapper } => addwrapper_node(&root, attr, wrapper)?,
+                Operation::Unwrap { attr } => rmwrapper_node(&root, attr)?,
+            };
+        }
+        self.root = root;
+        Ok(())
+    }
+
+    pub fn write(&mut self, query: &str, val: &str) -> Result<(), Error> {
+        self.root = write_node(&self.root, query, val)?;
+        Ok(())
+    }
+
+    pub fn write_inner(&mut self, query: &str, val: &str) -> Result<(), Error> {
+        self.root = write_inner_node(&self.root, query, val)?;
+        Ok(())
+    }
+
+    pub fn write_typed(&mut self, query: &str, val: &NixValue) -> Result<(), Error> {
+        let val = val.to_nix().map_err(|e| e.withattr(query))?;
+        self.write(query, &val)
+    }
+
+    pub fn deref(&mut self, query: &str) -> Result<(), Error> {
+        self.root = deref_node(&self.root, query)?;
+        Ok(())
+    }
+
+    pub fn addtoarr(&mut self, query: &str, items: Vec<String>) -> Result<(), Error> {
+        self.root = addtoarr_node(&self.root, query, items)?;
+        Ok(())
+    }
+
+    pub fn insertarr(
+        &mut self,
+        query: &str,
+        items: Vec<String>,
+        position: &Position,
+    ) -> Result<(), Error> {
+        self.root = insertarr_node(&self.root, query, items, position)?;
+        Ok(())
+    }
+
+    // Returns the items that were added
+    pub fn ensurearr(
+        &mut self,
+        query: &str,
+        items: Vec<String>,
+        position: &Position,
+    ) -> Result<Vec<String>, Error> {
+        let (root, added) = ensurearr_node(&self.root, query, items, position)?;
+        self.root = root;
+        Ok(added)
+    }
+
+    // Returns the items that were not in the list
+    pub fn rmarr(&mut self, query: &str, items: Vec<String>) -> Result<Vec<String>, Error> {
+        let (root, missing) = rmarr_node(&self.root, query, items, false)?;
+        self.root = root;
+        Ok(missing)
+    }
+
+    pub fn replacearr(&mut self, query: &str, old: &str, new: &str) -> Result<(), Error> {
+        self.root = replacearr_node(&self.root, query, old, new)?;
+        Ok(())
+    }
+
+    pub fn addwrapper(&mut self, query: &str, wrapper: &Wrapper) -> Result<(), Error> {
+        self.root = addwrapper_node(&self.root, query, wrapper)?;
+        Ok(())
+    }
+
+    pub fn rmwrapper(&mut self, query: &str) -> Result<(), Error> {
+        self.root = rmwrapper_node(&self.root, query)?;
+        Ok(())
+    }
+
+    pub fn setcomment(&mut self, query: &str, comment: Option<&str>) -> Result<(), Error> {
+        self.root = setcomment_node(&self.root, query, comment)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root)
+    }
+}
@@ -1,66 +1,319 @@
-pub fn printread(f: &str, attr: &str) -> Result<String, read::ReadError> {
-    match read::readvalue(f, attr) {
-        Ok(x) => Ok(x),
-        Err(e) => return Err(e),
-    }
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use rnix::{SyntaxKind, SyntaxNode};
+
+use crate::{
+    location::Location,
+    options::ValidationError,
+    parse::{attrs, findbinding, parseconfig, quotesegment, resolvepath, AttrPath, ParseError, Resolution},
+    read::findvalue,
+    wrapper::unwrap,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The file has syntax errors or is not an attribute set
+    Parse(ParseError),
+    // A value given to write has syntax errors
+    InvalidValue(ParseError),
+    // A typed value has no Nix literal, like a float that is not finite
+    Unwritable(String),
+    NoAttr,
+    // The attribute to write has no valid path, with the reason why
+    InvalidAttr(String),
+    // Part of the attribute's path is bound to something other than an attribute set
+    NotAttrSet,
+    // The value is not a list
+    Array,
+    // Setting an attribute set to a single value would drop its attributes
+    WriteValueToSet,
+    // The list has no elements like these
+    NoElement(Vec<String>),
+    // The index to insert at is past the end of the list
+    IndexOutOfRange(usize),
+    // The attribute is not a known option, the value does not fit it or a package
+    // added to it does not exist. Boxed as it is much larger than the other kinds.
+    Validation(Box<ValidationError>),
 }
 
-pub fn writeerr(e: write::WriteError, file: &str, attr: &str) {
-    let msg;
-    match e {
-        write::WriteError::ParseError => {
-            msg = format!(
-                "failed to parse '{}' as a nix configuration file",
-                file.purple()
-            );
-            printerror(&msg);
+// An error from reading or writing, with the attribute, file and the part of the
+// file it is about when they are known. Functions that take the source fill in the
+// attribute and span; the file is added by those that read it, like `Workspace`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub attr: Option<String>,
+    pub file: Option<PathBuf>,
+    // The node the error is about, such as the value that is not a list or the
+    // deepest part of a missing attribute's path that exists. Boxed to keep results
+    // holding an error small.
+    pub span: Option<Box<Location>>,
+    // The longest part of a missing attribute's path that exists
+    pub prefix: Option<AttrPath>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            attr: None,
+            file: None,
+            span: None,
+            prefix: None,
         }
-        write::WriteError::NoAttr => {
-            msg = format!(
-                "cannot modify '{}' : {}",
-                attr.purple(),
-                "No such attribute".purple()
-            );
-            printerror(&msg);
+    }
+
+    pub fn withattr(mut self, attr: &str) -> Self {
+        self.attr = Some(attr.to_string());
+        self
+    }
+
+    pub fn withfile(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        if let Some(span) = &mut self.span {
+            span.file = self.file.clone();
         }
-        write::WriteError::ArrayError => {
-            msg = format!(
-                "cannot add an element to '{}' : {}",
-                attr.purple(),
-                "Is this value an array?".purple()
-            );
-            printerror(&msg);
+        self
+    }
+
+    // Find the node the error is about in `source`, the file it happened in
+    pub fn locate(mut self, source: &str) -> Self {
+        let configbase = parseconfig(source).ok();
+        let span = match (&self.kind, &self.attr, configbase) {
+            (ErrorKind::Parse(e), _, _) => e.errors.first().map(|x| x.location.clone()),
+            (ErrorKind::NoAttr | ErrorKind::NotAttrSet, Some(attr), Some(configbase)) => {
+                match resolvepath(&configbase, attr) {
+                    Resolution::Missing { prefix, .. } => {
+                        self.kind = ErrorKind::NoAttr;
+                        let span = keyspan(source, &configbase, &prefix);
+                        self.prefix = Some(prefix);
+                        span
+                    }
+                    Resolution::NotAttrSet { prefix, .. } => {
+                        self.kind = ErrorKind::NotAttrSet;
+                        let span = valuespan(source, &configbase, &prefix);
+                        self.prefix = Some(prefix);
+                        span
+                    }
+                    Resolution::Found => None,
+                }
+            }
+            (
+                ErrorKind::Array
+                | ErrorKind::WriteValueToSet
+                | ErrorKind::NoElement(_)
+                | ErrorKind::IndexOutOfRange(_),
+                Some(attr),
+                Some(configbase),
+            ) => {
+                valuespan(source, &configbase, &AttrPath::parse(attr))
+            }
+            // Only a value that is already in the file can be pointed at
+            (ErrorKind::Validation(e), _, Some(configbase)) => {
+                valuespan(source, &configbase, &AttrPath::parse(e.attr()))
+            }
+            _ => None,
+        };
+        self.span = span.map(|mut x| {
+            x.file = self.file.clone();
+            Box::new(x)
+        });
+        self
+    }
+
+    // The first segment of the attribute's path that does not exist
+    pub fn missing(&self) -> Option<String> {
+        let prefix = self.prefix.as_ref()?;
+        AttrPath::parse(self.attr.as_ref()?).segments().get(prefix.len()).cloned()
+    }
+
+    // What is wrong at the span
+    pub fn label(&self) -> Option<String> {
+        self.span.as_ref()?;
+        match &self.kind {
+            ErrorKind::Parse(e) => e.errors.first().map(|x| x.message.clone()),
+            ErrorKind::InvalidValue(_) | ErrorKind::Unwritable(_) | ErrorKind::InvalidAttr(_) => None,
+            ErrorKind::NoAttr => Some(format!(
+                "`{}` has no attribute `{}`",
+                self.prefix.as_ref()?,
+                quotesegment(&self.missing()?)
+            )),
+            ErrorKind::NotAttrSet => Some("this is not an attribute set".to_string()),
+            ErrorKind::Array => Some("this is not a list".to_string()),
+            ErrorKind::WriteValueToSet => Some("this is an attribute set".to_string()),
+            ErrorKind::NoElement(_) => Some("in this list".to_string()),
+            ErrorKind::IndexOutOfRange(_) => Some("this list".to_string()),
+            ErrorKind::Validation(e) => match e.as_ref() {
+                ValidationError::UnknownPackage { .. } => Some("in this list".to_string()),
+                _ => Some("this is the current value".to_string()),
+            },
+        }
+    }
+
+    // Short name of the kind of error, as printed in JSON output
+    pub fn code(&self) -> &'static str {
+        match &self.kind {
+            ErrorKind::Parse(_) => "parse_error",
+            ErrorKind::InvalidValue(_) => "invalid_value",
+            ErrorKind::Unwritable(_) => "unwritable_value",
+            ErrorKind::NoAttr => "no_attr",
+            ErrorKind::InvalidAttr(_) => "invalid_attr",
+            ErrorKind::NotAttrSet => "not_attrset",
+            ErrorKind::Array => "array_error",
+            ErrorKind::WriteValueToSet => "write_value_to_set",
+            ErrorKind::NoElement(_) => "no_element",
+            ErrorKind::IndexOutOfRange(_) => "index_out_of_range",
+            ErrorKind::Validation(e) => match e.as_ref() {
+                ValidationError::UnknownOption { .. } => "unknown_option",
+                ValidationError::WrongType { .. } => "wrong_type",
+                ValidationError::ReadOnly { .. } => "read_only",
+                ValidationError::UnknownPackage { .. } => "unknown_package",
+            },
         }
     }
-}
 
-pub fn readerr(e: read::ReadError, file: &str, attr: &str) {
-    let msg;
-    match e {
-        read::ReadError::ParseError => {
-            msg = format!(
-                "failed to parse '{}' as a nix configuration file",
-                file.purple()
-            );
-            printerror(&msg);
+    // Render the error like a compiler would, with the line of `source` it is about
+    // and a caret under the node
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("error: {}", self);
+        let span = match &self.span {
+            Some(x) => x,
+            None => {
+                if let Some(file) = &self.file {
+                    out.push_str(&format!("\n --> {}", file.display()));
+                }
+                return out;
+            }
+        };
+        let file = match &self.file {
+            Some(x) => x.display().to_string(),
+            None => "<input>".to_string(),
+        };
+        let text = source.lines().nth(span.line - 1).unwrap_or_default();
+        let gutter = " ".repeat(span.line.to_string().len());
+        // Spans over several lines are marked up to the end of their first line
+        let end = if span.endline == span.line {
+            span.endcolumn
+        } else {
+            text.chars().count() + 1
+        };
+        // Keep tabs so the caret lines up with the source
+        let pad = text
+            .chars()
+            .take(span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let caret = "^".repeat(end.saturating_sub(span.column).max(1));
+        out.push_str(&format!("\n{}--> {}:{}:{}", gutter, file, span.line, span.column));
+        out.push_str(&format!("\n{} |", gutter));
+        out.push_str(&format!("\n{} | {}", span.line, text));
+        out.push_str(&format!("\n{} | {}{}", gutter, pad, caret));
+        if let Some(label) = self.label() {
+            out.push_str(&format!(" {}", label));
         }
-        read::ReadError::NoAttr => {
-            msg = format!(
-                "cannot read attribute '{}' in '{}' : {}",
-                attr.purple(),
-                file.purple(),
-                "No attribute found".purple()
-            );
-            printerror(&msg);
+        out
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attr = self.attr.as_deref().unwrap_or_default();
+        match &self.kind {
+            ErrorKind::Parse(e) if e.errors.is_empty() => {
+                write!(f, "not a nix configuration, {}", e)
+            }
+            ErrorKind::Parse(e) if self.span.is_some() && e.errors.len() > 1 => {
+                write!(f, "syntax error, and {} more", e.errors.len() - 1)
+            }
+            ErrorKind::Parse(_) if self.span.is_some() => write!(f, "syntax error"),
+            ErrorKind::Parse(e) => write!(f, "syntax error: {}", e),
+            ErrorKind::InvalidValue(e) => write!(f, "invalid value for `{}`: {}", attr, e),
+            ErrorKind::Unwritable(x) => {
+                write!(f, "cannot set `{}` to {}, Nix has no literal for it", attr, x)
+            }
+            ErrorKind::NoAttr => match (&self.prefix, self.missing()) {
+                // Without a span the label saying what is missing is not shown
+                (Some(prefix), Some(missing)) if self.span.is_none() && !prefix.is_empty() => write!(
+                    f,
+                    "no attribute `{}`, `{}` has no attribute `{}`",
+                    attr,
+                    prefix,
+                    quotesegment(&missing)
+                ),
+                _ => write!(f, "no attribute `{}`", attr),
+            },
+            ErrorKind::InvalidAttr(x) => write!(f, "invalid attribute `{}`, {}", attr, x),
+            ErrorKind::NotAttrSet => match &self.prefix {
+                Some(prefix) => write!(
+                    f,
+                    "cannot look up `{}`, `{}` is not an attribute set",
+                    attr, prefix
+                ),
+                None => write!(f, "cannot look up `{}` in a value that is not an attribute set", attr),
+            },
+            ErrorKind::Array => write!(f, "`{}` is not a list", attr),
+            ErrorKind::WriteValueToSet => {
+                write!(f, "cannot set `{}` to a value, it is an attribute set", attr)
+            }
+            ErrorKind::NoElement(x) if x.len() == 1 => {
+                write!(f, "`{}` has no element `{}`", attr, x[0])
+            }
+            ErrorKind::NoElement(x) => write!(f, "`{}` has no elements `{}`", attr, x.join("`, `")),
+            ErrorKind::IndexOutOfRange(i) => {
+                write!(f, "cannot insert at index {}, it is past the end of `{}`", i, attr)
+            }
+            ErrorKind::Validation(e) => write!(f, "{}", e),
         }
     }
 }
 
-pub fn nofileerr(file: &str) {
-    let msg = format!("reading '{}': {}", file.purple(), "No such file".purple());
-    printerror(&msg);
+impl std::error::Error for Error {}
+
+impl From<ValidationError> for Error {
+    fn from(e: ValidationError) -> Self {
+        let attr = e.attr().to_string();
+        Error::new(ErrorKind::Validation(Box::new(e))).withattr(&attr)
+    }
+}
+
+// Syntax errors in the file being read, at the first of them
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        let span = e.errors.first().map(|x| Box::new(x.location.clone()));
+        let mut out = Error::new(ErrorKind::Parse(e));
+        out.span = span;
+        out
+    }
+}
+
+// An error about the attribute `attr` of the file `source`, pointing at the part of
+// the file it is about
+pub(crate) fn attrerror(kind: ErrorKind, attr: &str, source: &str) -> Error {
+    Error::new(kind).withattr(attr).locate(source)
+}
+
+// A value given for `attr` that is not valid Nix
+pub(crate) fn valueerror(e: ParseError, attr: &str) -> Error {
+    Error::new(ErrorKind::InvalidValue(e)).withattr(attr)
+}
+
+// The key of the first binding that defines `prefix` or something inside it
+fn keyspan(source: &str, configbase: &SyntaxNode, prefix: &AttrPath) -> Option<Location> {
+    if prefix.is_empty() {
+        return None;
+    }
+    let (_, value, _) = attrs(configbase, None).find(|(p, _, _)| p.starts_with(prefix))?;
+    let key = value.parent()?.first_child()?;
+    if key.kind() != SyntaxKind::NODE_KEY {
+        return None;
+    }
+    Some(Location::new(source, key.text_range()))
 }
 
-fn printerror(msg: &str) {
-    println!("{} {}", "error:".red(), msg);
+fn valuespan(source: &str, configbase: &SyntaxNode, path: &AttrPath) -> Option<Location> {
+    let value = findvalue(&findbinding(configbase, &path.to_string())?)?;
+    Some(Location::new(source, unwrap(&value).0.text_range()))
 }
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use rnix::{SyntaxKind, SyntaxNode, SyntaxToken};
+
+// The indentation added per nesting level in the file, such as two spaces or a tab.
+// Defaults to two spaces if the file has no nested bindings or list elements.
+pub(crate) fn indentunit(node: &SyntaxNode) -> String {
+    let root = match node.ancestors().last() {
+        Some(x) => x,
+        None => node.clone(),
+    };
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for node in root.descendants() {
+        let parent = match node.parent() {
+            Some(x) => x,
+            None => continue,
+        };
+        let nested = matches!(
+            node.kind(),
+            SyntaxKind::NODE_KEY_VALUE | SyntaxKind::NODE_INHERIT
+        ) || parent.kind() == SyntaxKind::NODE_LIST;
+        if !nested {
+            continue;
+        }
+        let indent = match startsline(&node) {
+            Some(x) => x,
+            None => continue,
+        };
+        let outer = lineindent(&parent);
+        if let Some(unit) = indent.strip_prefix(outer.as_str()) {
+            if !unit.is_empty() {
+                *counts.entry(unit.to_string()).or_default() += 1;
+            }
+        }
+    }
+    // Prefer the smaller unit on ties, since deeper levels are multiples of it
+    match counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.len().cmp(&a.0.len())))
+    {
+        Some((unit, _)) => unit,
+        None => "  ".to_string(),
+    }
+}
+
+// Indentation of the line `node` starts on
+pub(crate) fn lineindent(node: &SyntaxNode) -> String {
+    let mut token = node.first_token().and_then(|t| t.prev_token());
+    while let Some(t) = token {
+        if let Some(i) = t.text().rfind('\n') {
+            return leading(&t.text()[i + 1..]);
+        }
+        token = t.prev_token();
+    }
+    String::new()
+}
+
+// Indentation of `node` if nothing but whitespace comes before it on its line
+pub(crate) fn startsline(node: &SyntaxNode) -> Option<String> {
+    let token = node.first_token()?.prev_token()?;
+    if token.kind() != SyntaxKind::TOKEN_WHITESPACE {
+        return None;
+    }
+    let i = token.text().rfind('\n')?;
+    Some(token.text()[i + 1..].to_string())
+}
+
+// Indentation for a new child of `node`, taken from the existing children that
+// start their own line, or one level deeper than the line `node` starts on
+pub(crate) fn childindent(node: &SyntaxNode, unit: &str) -> String {
+    match node.children().filter_map(|x| startsline(&x)).last() {
+        Some(x) => x,
+        None => format!("{}{}", lineindent(node), unit),
+    }
+}
+
+// Re-indent a value written as if it started at column 0, so that it lines up
+// with a line indented by `indent` in a file indenting by `unit`
+pub(crate) fn reindent(val: &str, indent: &str, unit: &str) -> String {
+    if !val.contains('\n') {
+        return val.to_string();
+    }
+    let root = rnix::parse(val).node();
+    let valunit = indentunit(&root);
+    let mut out = String::new();
+    let mut line = indent.to_string();
+    let mut skip = None;
+    for token in root.descendants_with_tokens().filter_map(|x| x.into_token()) {
+        if let Some(end) = skip {
+            if token.text_range().end() <= end {
+                continue;
+            }
+            skip = None;
+        }
+        match token.kind() {
+            SyntaxKind::TOKEN_WHITESPACE if token.text().contains('\n') => {
+                let parts = token.text().split('\n').collect::<Vec<_>>();
+                out.push_str(parts[0]);
+                for _ in 1..parts.len() - 1 {
+                    out.push('\n');
+                }
+                line = relevel(parts[parts.len() - 1], indent, unit, &valunit);
+                out.push('\n');
+                out.push_str(&line);
+            }
+            SyntaxKind::TOKEN_STRING_START if token.text() == "''" => match indentedstr(&token) {
+                Some(node) => {
+                    out.push_str(&reindentstr(&node.to_string(), &line, unit));
+                    skip = Some(node.text_range().end());
+                }
+                None => out.push_str(token.text()),
+            },
+            _ => out.push_str(token.text()),
+        }
+    }
+    out
+}
+
+fn indentedstr(token: &SyntaxToken) -> Option<SyntaxNode> {
+    let node = token.parent();
+    if node.kind() == SyntaxKind::NODE_STRING && node.text().contains_char('\n') {
+        Some(node)
+    } else {
+        None
+    }
+}
+
+fn relevel(ws: &str, indent: &str, unit: &str, valunit: &str) -> String {
+    let tabs = ws.chars().take_while(|c| *c == '\t').count();
+    let (levels, rest) = if tabs > 0 {
+        (tabs, &ws[tabs..])
+    } else {
+        let spaces = ws.chars().take_while(|c| *c == ' ').count();
+        let width = valunit.len().max(1);
+        (spaces / width, &ws[spaces - spaces % width..])
+    };
+    format!("{}{}{}", indent, unit.repeat(levels), rest)
+}
+
+// Move the lines of an indented string so its contents sit one level deeper than
+// `base` and the closing quotes line up with it. Nix strips the common indentation
+// of these strings, so the value stays the same.
+fn reindentstr(s: &str, base: &str, unit: &str) -> String {
+    let lines = s.split('\n').collect::<Vec<_>>();
+    if lines[0] != "''" {
+        return s.to_string();
+    }
+    let last = lines.len() - 1;
+    let closing = lines[last].trim_start() == "''";
+    let content = if closing { &lines[1..last] } else { &lines[1..] };
+    // Tabs are not stripped by Nix, so moving them would change the string
+    if base.contains('\t') || unit.contains('\t') || content.iter().any(|l| leading(l).contains('\t')) {
+        return s.to_string();
+    }
+    let min = content
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| leading(l).len())
+        .min()
+        .unwrap_or(0);
+    let mut out = vec![lines[0].to_string()];
+    for l in content {
+        if l.len() <= min && l.trim().is_empty() {
+            out.push(String::new());
+        } else {
+            out.push(format!("{}{}{}", base, unit, &l[min..]));
+        }
+    }
+    if closing {
+        out.push(format!("{}''", base));
+    }
+    out.join("\n")
+}
+
+fn leading(s: &str) -> String {
+    s.chars().take_while(|c| *c == ' ' || *c == '\t').collect()
+}
@@ -1,5 +1,15 @@
+pub mod comment;
+pub mod document;
+pub mod errors;
+mod indent;
+pub mod location;
+pub mod options;
+pub mod packages;
 pub mod parse;
 pub mod read;
+pub mod value;
+pub mod workspace;
+pub mod wrapper;
 pub mod write;
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use rnix::TextRange;
+
+// A span of source text. Offsets are in bytes, lines and columns start at 1 and
+// columns count characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    // Only known when the text was read from a file, as in a `Workspace`
+    pub file: Option<PathBuf>,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub endline: usize,
+    pub endcolumn: usize,
+}
+
+impl Location {
+    // `range` is relative to the start of `text`, which is the whole file
+    pub fn new(text: &str, range: TextRange) -> Self {
+        let (start, end) = (usize::from(range.start()), usize::from(range.end()));
+        let (line, column) = linecol(text, start);
+        let (endline, endcolumn) = linecol(text, end);
+        Location {
+            file: None,
+            start,
+            end,
+            line,
+            column,
+            endline,
+            endcolumn,
+        }
+    }
+}
+
+// Where an attribute is defined
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttrLocation {
+    // The key as written, which for `a.b = ...` covers all of `a.b`
+    pub key: Location,
+    // The value without any `mkIf`, `mkDefault`, ... wrappers
+    pub value: Location,
+    // The whole binding, up to and including the semicolon
+    pub binding: Location,
+}
+
+impl AttrLocation {
+    pub(crate) fn setfile(&mut self, file: PathBuf) {
+        self.key.file = Some(file.clone());
+        self.value.file = Some(file.clone());
+        self.binding.file = Some(file);
+    }
+}
+
+fn linecol(text: &str, offset: usize) -> (usize, usize) {
+    let before = match text.get(..offset) {
+        Some(x) => x,
+        None => text,
+    };
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(i) => before[i + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, column)
+}
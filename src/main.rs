@@ -1,172 +1,935 @@
-use clap::{self, ArgGroup, Parser};
-use nix_editor::{write::deref, write::write, write::addtoarr};
-use std::{fs, path::Path, io::Write};
+use clap::{self, Parser, Subcommand};
+use nix_editor::{
+    comment::getcomments,
+    document::{Document, Operation},
+    errors::{Error, ErrorKind},
+    location::Location,
+    options::Options,
+    packages::Packages,
+    parse::{findattr, get_collection, parseconfig, AttrPath},
+    read::{findvalue, getarrsegments, getarrvals, readlocation},
+    value::{tostring, NixValue},
+    workspace::Workspace,
+    wrapper::{outerwrappers, unwrap, Wrapper},
+    write::{addwrapper, deref, ensurearr, insertarr, removearr, replacearr, rmwrapper, Position},
+};
+use rnix::SyntaxNode;
+use serde_json::json;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 use owo_colors::*;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
-#[clap(group(ArgGroup::new("write").args(&["val", "deref", "arr"])))]
 struct Args {
     /// Configuration file to read
     file: String,
 
-    /// Nix configuration option arribute
-    attribute: String,
+    #[clap(subcommand)]
+    command: Command,
 
-    /// Value to write
-    #[clap(short, long)]
-    val: Option<String>,
+    /// Output file for modified config or read value
+    #[clap(short, long, global = true)]
+    output: Option<String>,
 
-    /// Element to add
-    #[clap(short, long)]
-    arr: Option<String>,
+    /// Modify the configuration file in place
+    #[clap(short, long, global = true, conflicts_with = "output")]
+    in_place: bool,
 
-    /// Dereference the value of the attribute
-    #[clap(short, long)]
-    deref: bool,
+    /// Keep a copy of the original file when editing in place
+    #[clap(
+        long,
+        global = true,
+        requires = "in-place",
+        value_name = "SUFFIX",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = ".bak"
+    )]
+    backup: Option<String>,
 
-    /// Output file for modified config or read value
-    #[clap(short, long)]
-    output: Option<String>,
+    /// Print read values and errors as JSON
+    #[clap(long, global = true)]
+    json: bool,
+
+    /// Follow imports and read or edit the module that defines the attribute
+    #[clap(long, global = true)]
+    imports: bool,
+
+    /// Check that set attributes are NixOS options and their values have the right type
+    #[clap(long, global = true, value_name = "OPTIONS_JSON")]
+    options: Option<String>,
+
+    /// Check that packages added to lists are in a package index, as written by
+    /// `nix-env -qa --json`
+    #[clap(long, global = true, value_name = "PACKAGES_JSON")]
+    packages: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the value of an attribute
+    Get {
+        /// Nix configuration option arribute
+        attribute: String,
+    },
+    /// Set an attribute to a value
+    Set {
+        /// Nix configuration option arribute
+        attribute: String,
+        /// Value to write
+        value: String,
+        /// Write the value as a string, quoting and escaping it as needed
+        #[clap(short, long)]
+        string: bool,
+        /// Comment to put above the attribute, replacing any existing one
+        #[clap(short, long)]
+        comment: Option<String>,
+        /// Keep mkDefault, mkForce, mkOverride and mkIf wrappers around the old value
+        #[clap(long)]
+        inner: bool,
+    },
+    /// Remove an attribute
+    Unset {
+        /// Nix configuration option arribute
+        attribute: String,
+    },
+    /// Add elements to a list
+    ListAdd {
+        /// Nix configuration option arribute
+        attribute: String,
+        /// Element to add
+        #[clap(short, long, required = true)]
+        arr: Vec<String>,
+        /// Insert before the element at this index
+        #[clap(long, conflicts_with_all = &["before", "after", "sorted"])]
+        index: Option<usize>,
+        /// Insert before this element
+        #[clap(long, conflicts_with_all = &["after", "sorted"])]
+        before: Option<String>,
+        /// Insert after this element
+        #[clap(long, conflicts_with = "sorted")]
+        after: Option<String>,
+        /// Insert in alphabetical order if the list is sorted
+        #[clap(long)]
+        sorted: bool,
+        /// Only add elements that are not in the list yet, and print the ones that were added
+        #[clap(long)]
+        ensure: bool,
+    },
+    /// Remove elements from a list
+    ListRemove {
+        /// Nix configuration option arribute
+        attribute: String,
+        /// Element to remove
+        #[clap(short, long, required = true)]
+        arr: Vec<String>,
+        /// Fail without removing anything if an element is not in the list
+        #[clap(long)]
+        strict: bool,
+    },
+    /// Replace an element of a list where it is
+    ListReplace {
+        /// Nix configuration option arribute
+        attribute: String,
+        /// Element to replace
+        old: String,
+        /// Element to put in its place
+        new: String,
+    },
+    /// Wrap the value of an attribute in mkDefault, mkForce or mkOverride
+    Wrap {
+        /// Nix configuration option arribute
+        attribute: String,
+        /// Wrapper to apply
+        #[clap(possible_values = ["default", "force", "override"])]
+        wrapper: String,
+        /// Priority for mkOverride
+        #[clap(required_if_eq("wrapper", "override"))]
+        priority: Option<i64>,
+    },
+    /// Remove mkDefault, mkForce or mkOverride from the value of an attribute
+    Unwrap {
+        /// Nix configuration option arribute
+        attribute: String,
+    },
+    /// Print the elements of a list
+    List {
+        /// Nix configuration option arribute
+        attribute: String,
+    },
+    /// Print all attributes, optionally only those under a prefix
+    Keys {
+        /// Nix configuration option arribute
+        attribute: Option<String>,
+    },
+    /// Print the files that define an attribute, following imports
+    Where {
+        /// Nix configuration option arribute
+        attribute: String,
+    },
 }
 
-fn writetofile(file: &str, out: &str) {
-    let mut outfile = std::fs::File::create(file).expect("create failed");
-    outfile.write_all(out.as_bytes()).expect("write failed");
+fn writetofile(file: &str, out: &str) -> io::Result<()> {
+    let mut outfile = std::fs::File::create(file)?;
+    outfile.write_all(out.as_bytes())
 }
 
-fn printread(f: &str, attr: &str) -> Result<String, nix_editor::read::ReadError> {
-    match nix_editor::read::readvalue(f, attr) {
-        Ok(x) => Ok(x),
-        Err(e) => Err(e),
+// Write to a temporary file next to the original and rename it into place,
+// so the configuration is never left truncated
+fn writeinplace(file: &str, out: &str, backup: Option<&str>) -> io::Result<()> {
+    // Replace the target of a symlink rather than the link itself
+    let path = fs::canonicalize(file)?;
+    let dir = match path.parent() {
+        Some(x) => x.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    let name = match path.file_name() {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => file.to_string(),
+    };
+    let tmppath = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+
+    if let Err(e) = writetmp(&tmppath, &path, out) {
+        let _ = fs::remove_file(&tmppath);
+        return Err(e);
     }
+    if let Some(suffix) = backup {
+        if let Err(e) = fs::copy(&path, dir.join(format!("{}{}", name, suffix))) {
+            let _ = fs::remove_file(&tmppath);
+            return Err(e);
+        }
+    }
+    if let Err(e) = fs::rename(&tmppath, &path) {
+        let _ = fs::remove_file(&tmppath);
+        return Err(e);
+    }
+    // Make sure the rename itself is on disk
+    #[cfg(unix)]
+    fs::File::open(&dir)?.sync_all()?;
+    Ok(())
+}
+
+fn writetmp(tmppath: &Path, original: &Path, out: &str) -> io::Result<()> {
+    let metadata = fs::metadata(original)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Never let others read the new file when the original is private
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(metadata.permissions().mode());
+    }
+    let mut tmpfile = options.open(tmppath)?;
+    tmpfile.write_all(out.as_bytes())?;
+    tmpfile.sync_all()?;
+    fs::set_permissions(tmppath, metadata.permissions())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Only root can give files away, so keeping the owner is best effort
+        let _ = std::os::unix::fs::chown(tmppath, Some(metadata.uid()), Some(metadata.gid()));
+    }
+    Ok(())
+}
+
+fn printread(f: &str, attr: &str, json: bool) -> Result<String, Error> {
+    if !json {
+        return nix_editor::read::readvalue(f, attr);
+    }
+    let configbase = parseconfig(f)?;
+    let root = configbase.ancestors().last();
+    let notfound = || Error::new(ErrorKind::NoAttr).withattr(attr).locate(f);
+    let node = match findattr(&configbase, attr) {
+        Some(x) => x,
+        None => return Err(notfound()),
+    };
+    let (value, inner) = match findvalue(&node) {
+        Some(x) => unwrap(&x),
+        None => return Err(notfound()),
+    };
+    let mut wrappers = outerwrappers(&node);
+    wrappers.extend(inner);
+    // Values collected from several bindings do not exist as one node in the file
+    let comments = if value.ancestors().last() == root {
+        getcomments(&node)
+    } else {
+        Default::default()
+    };
+    let out = json!({
+        "attr": attr,
+        "value": value.to_string(),
+        "kind": jsonkind(&value),
+        "location": match readlocation(f, attr) {
+            Ok(x) => json!({
+                "key": jsonlocation(&x.key),
+                "value": jsonlocation(&x.value),
+                "binding": jsonlocation(&x.binding),
+            }),
+            Err(_) => serde_json::Value::Null,
+        },
+        "comment": { "leading": comments.leading, "trailing": comments.trailing },
+        "wrappers": wrappers.iter().map(jsonwrapper).collect::<Vec<_>>(),
+    });
+    Ok(out.to_string())
+}
+
+fn setvalue(
+    f: &str,
+    attr: &str,
+    value: &str,
+    string: bool,
+    inner: bool,
+    comment: Option<&str>,
+) -> Result<String, Error> {
+    let mut doc = Document::parse(f)?;
+    let value = if string {
+        tostring(value)
+    } else {
+        value.to_string()
+    };
+    if inner {
+        doc.write_inner(attr, &value)?;
+    } else {
+        doc.write(attr, &value)?;
+    }
+    if comment.is_some() {
+        doc.setcomment(attr, comment)?;
+    }
+    Ok(doc.to_string())
+}
+
+fn jsonkind(node: &SyntaxNode) -> &'static str {
+    match NixValue::from_node(node) {
+        NixValue::String(_) => "string",
+        NixValue::Bool(_) => "bool",
+        NixValue::Int(_) => "int",
+        NixValue::List(_) => "list",
+        NixValue::AttrSet(_) => "set",
+        _ => "expr",
+    }
+}
+
+fn jsonlocation(loc: &Location) -> serde_json::Value {
+    json!({
+        "start": loc.start,
+        "end": loc.end,
+        "line": loc.line,
+        "column": loc.column,
+        "end_line": loc.endline,
+        "end_column": loc.endcolumn,
+    })
+}
+
+fn jsonwrapper(wrapper: &Wrapper) -> serde_json::Value {
+    match wrapper {
+        Wrapper::If(cond) => json!({ "type": "mkIf", "condition": cond }),
+        Wrapper::Merge => json!({ "type": "mkMerge" }),
+        Wrapper::Default => json!({ "type": "mkDefault" }),
+        Wrapper::Force => json!({ "type": "mkForce" }),
+        Wrapper::Override(priority) => json!({ "type": "mkOverride", "priority": priority }),
+    }
+}
+
+fn printlist(f: &str, attr: &str, json: bool) -> Result<String, Error> {
+    if json {
+        // Each literal list with the condition it is added under, if any
+        let segments = getarrsegments(f, attr)?
+            .into_iter()
+            .map(|x| json!({ "elems": x.elems, "condition": x.condition }))
+            .collect::<Vec<_>>();
+        Ok(json!(segments).to_string())
+    } else {
+        Ok(getarrvals(f, attr)?.join("\n"))
+    }
+}
+
+fn getkeys(f: &str, attr: Option<&str>) -> Result<Vec<String>, Error> {
+    let prefix = attr.map(AttrPath::parse);
+    Ok(get_collection(f.to_string())?
+        .into_keys()
+        .filter(|k| match &prefix {
+            Some(p) => AttrPath::parse(k).starts_with(p),
+            None => true,
+        })
+        .collect())
 }
 
-fn writeerr(e: nix_editor::write::WriteError, file: &str, attr: &str) {
-    let msg;
-    match e {
-        nix_editor::write::WriteError::ParseError => {
-            msg = format!(
-                "failed to parse '{}' as a nix configuration file",
-                file.purple()
+fn printkeys(f: &str, attr: Option<&str>, json: bool) -> Result<String, Error> {
+    let mut keys = getkeys(f, attr)?;
+    keys.sort();
+    if json {
+        Ok(json!(keys).to_string())
+    } else {
+        Ok(keys.join("\n"))
+    }
+}
+
+fn getpositionarg(
+    index: Option<usize>,
+    before: &Option<String>,
+    after: &Option<String>,
+    sorted: bool,
+) -> Position {
+    match (index, before, after) {
+        (Some(i), _, _) => Position::Index(i),
+        (_, Some(x), _) => Position::Before(x.to_string()),
+        (_, _, Some(x)) => Position::After(x.to_string()),
+        _ if sorted => Position::Sorted,
+        _ => Position::End,
+    }
+}
+
+fn getwrapperarg(wrapper: &str, priority: Option<i64>) -> Wrapper {
+    match wrapper {
+        "default" => Wrapper::Default,
+        "force" => Wrapper::Force,
+        _ => Wrapper::Override(priority.unwrap_or_default().to_string()),
+    }
+}
+
+// Run a command on the configuration and every module it imports. Edits are
+// written to the modules that define the attributes.
+fn runimports(args: &Args, f: &str) {
+    if let Err(e) = parseconfig(f) {
+        printliberr(e.into(), &args.file, args.json);
+        std::process::exit(1);
+    }
+    let mut ws = match Workspace::open(Path::new(&args.file)) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!(
+                "reading '{}': {}",
+                highlight(&args.file, args.json),
+                highlight(&e.to_string(), args.json)
             );
-            printerror(&msg);
+            printerror("io_error", &msg, &args.file, None, args.json);
+            std::process::exit(1);
         }
-        nix_editor::write::WriteError::NoAttr => {
-            msg = format!(
-                "cannot modify '{}' : {}",
-                attr.purple(),
-                "No such attribute".purple()
-            );
-            printerror(&msg);
+    };
+    printskipped(ws.skipped(), args.json);
+    // Reported once the files are written, as nothing is added if that fails
+    let mut added = None;
+    let mut missing = None;
+    let ops = match &args.command {
+        Command::Get { attribute } | Command::List { attribute } => {
+            let (path, attr) = match ws.find(attribute) {
+                Some(x) => x,
+                None => {
+                    attrerr(Error::new(ErrorKind::NoAttr), &args.file, attribute, args.json);
+                    std::process::exit(1);
+                }
+            };
+            let f = ws.source(&path).unwrap_or_default();
+            let out = match &args.command {
+                Command::Get { .. } => printread(&f, &attr, args.json),
+                _ => printlist(&f, &attr, args.json),
+            };
+            match out {
+                Ok(x) if args.json && matches!(args.command, Command::Get { .. }) => {
+                    let mut value: serde_json::Value = serde_json::from_str(&x).unwrap_or_default();
+                    value["file"] = json!(path);
+                    printoutput(args, &value.to_string());
+                }
+                Ok(x) => printoutput(args, &x),
+                Err(e) => {
+                    attrerr(e, &path.to_string_lossy(), attribute, args.json);
+                    std::process::exit(1);
+                }
+            }
+            return;
         }
-        nix_editor::write::WriteError::ArrayError => {
-            msg = format!(
-                "cannot add an element to '{}' : {}",
-                attr.purple(),
-                "Is this value an array?".purple()
-            );
-            printerror(&msg);
+        Command::Keys { attribute } => {
+            let mut keys = vec![];
+            for path in ws.files() {
+                let f = ws.source(path).unwrap_or_default();
+                match getkeys(&f, attribute.as_deref()) {
+                    Ok(x) => keys.extend(x),
+                    Err(e) => {
+                        attrerr(e, &path.to_string_lossy(), attribute.as_deref().unwrap_or_default(), args.json);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            keys.sort();
+            keys.dedup();
+            if args.json {
+                printoutput(args, &json!(keys).to_string());
+            } else {
+                printoutput(args, &keys.join("\n"));
+            }
+            return;
+        }
+        Command::Where { attribute } => {
+            let files = ws.locate(attribute);
+            if files.is_empty() {
+                attrerr(Error::new(ErrorKind::NoAttr), &args.file, attribute, args.json);
+                std::process::exit(1);
+            }
+            if args.json {
+                printoutput(args, &json!(files).to_string());
+            } else {
+                let files = files.iter().map(|x| x.to_string_lossy()).collect::<Vec<_>>();
+                printoutput(args, &files.join("\n"));
+            }
+            return;
         }
-        nix_editor::write::WriteError::WriteValueToSet => {
-            msg = format!(
-                "cannot modify '{}' : {}",
-                attr.purple(),
-                "Cannot set an attribute-set to a value".purple()
+        Command::Set { attribute, value, string, comment, inner } => {
+            let value = if *string {
+                tostring(value)
+            } else {
+                value.to_string()
+            };
+            let mut ops = vec![if *inner {
+                Operation::SetInner { attr: attribute.clone(), value }
+            } else {
+                Operation::Set { attr: attribute.clone(), value }
+            }];
+            if comment.is_some() {
+                ops.push(Operation::Comment { attr: attribute.clone(), comment: comment.clone() });
+            }
+            ops
+        }
+        Command::Unset { attribute } => vec![Operation::Unset { attr: attribute.clone() }],
+        Command::ListAdd { attribute, arr, index, before, after, sorted, ensure: true } => {
+            added = match ws.missingarr(attribute, arr) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    printliberr(e, &args.file, args.json);
+                    std::process::exit(1);
+                }
+            };
+            vec![Operation::ListEnsure {
+                attr: attribute.clone(),
+                items: arr.clone(),
+                position: getpositionarg(*index, before, after, *sorted),
+            }]
+        }
+        Command::ListAdd { attribute, arr, index, before, after, sorted, .. } => vec![Operation::ListInsert {
+            attr: attribute.clone(),
+            items: arr.clone(),
+            position: getpositionarg(*index, before, after, *sorted),
+        }],
+        Command::ListRemove { attribute, arr, strict } => {
+            // With `--strict` the edit fails instead
+            if !*strict {
+                missing = match ws.absentarr(attribute, arr) {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        printliberr(e, &args.file, args.json);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            vec![Operation::ListRemove {
+                attr: attribute.clone(),
+                items: arr.clone(),
+                strict: *strict,
+            }]
+        }
+        Command::ListReplace { attribute, old, new } => vec![Operation::ListReplace { attr: attribute.clone(), old: old.clone(), new: new.clone() }],
+        Command::Wrap { attribute, wrapper, priority } => vec![Operation::Wrap { attr: attribute.clone(), wrapper: getwrapperarg(wrapper, *priority) }],
+        Command::Unwrap { attribute } => vec![Operation::Unwrap { attr: attribute.clone() }],
+    };
+    if let Err(e) = ws.apply(&ops) {
+        printliberr(e, &args.file, args.json);
+        std::process::exit(1);
+    }
+    let changed = ws.changed();
+    // Several files can not be told apart in one output
+    if changed.len() > 1 && !args.in_place {
+        let files = changed
+            .iter()
+            .map(|(x, _)| format!("'{}'", highlight(&x.to_string_lossy(), args.json)))
+            .collect::<Vec<_>>();
+        let msg = format!("the edit changes {}, use --in-place to write them", files.join(", "));
+        printerror("multiple_files", &msg, &args.file, None, args.json);
+        std::process::exit(1);
+    }
+    // The output would not say which file it is
+    if let Some((path, _)) = changed.iter().find(|(x, _)| *x != ws.entry()) {
+        if !args.in_place {
+            let msg = format!(
+                "the edit changes the imported file '{}', use --in-place to write it",
+                highlight(&path.to_string_lossy(), args.json)
             );
-            printerror(&msg);
+            printerror("imported_file", &msg, &args.file, None, args.json);
+            std::process::exit(1);
+        }
+    }
+    for (path, out) in changed {
+        if args.in_place {
+            if let Err(e) = writeinplace(&path.to_string_lossy(), &out, args.backup.as_deref()) {
+                writeerr(&path.to_string_lossy(), &e, args.json);
+                std::process::exit(1);
+            }
+        } else {
+            printoutput(args, &out);
         }
     }
+    if let Some(x) = added {
+        printadded(&x, args.json);
+    }
+    if let Some(x) = missing {
+        printmissing(&x, args.json);
+    }
 }
 
-fn readerr(e: nix_editor::read::ReadError, file: &str, attr: &str) {
-    let msg;
-    match e {
-        nix_editor::read::ReadError::ParseError => {
-            msg = format!(
-                "failed to parse '{}' as a nix configuration file",
-                file.purple()
+// Check the attribute and value given to `set` against the options file, if there is one
+fn checkoptions(args: &Args) {
+    let (file, attribute, value) = match (&args.options, &args.command) {
+        (Some(file), Command::Set { attribute, value, string, .. }) => {
+            let value = if *string {
+                tostring(value)
+            } else {
+                value.to_string()
+            };
+            (file, attribute, value)
+        }
+        _ => return,
+    };
+    let options = match Options::load(Path::new(file)) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!(
+                "reading '{}': {}",
+                highlight(file, args.json),
+                highlight(&e.to_string(), args.json)
             );
-            printerror(&msg);
-        }
-        nix_editor::read::ReadError::NoAttr => {
-            msg = format!(
-                "cannot read attribute '{}' in '{}' : {}",
-                attr.purple(),
-                file.purple(),
-                "No attribute found".purple()
+            printerror("io_error", &msg, file, None, args.json);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = options.check(attribute, &value) {
+        printliberr(Error::from(e), &args.file, args.json);
+        std::process::exit(1);
+    }
+}
+
+// Check the packages given to `list-add` or `list-replace` against the package
+// index, if there is one
+fn checkpackages(args: &Args) {
+    let (file, attribute, arr) = match (&args.packages, &args.command) {
+        (Some(file), Command::ListAdd { attribute, arr, .. }) => (file, attribute, arr.clone()),
+        (Some(file), Command::ListReplace { attribute, new, .. }) => (file, attribute, vec![new.clone()]),
+        _ => return,
+    };
+    let packages = match Packages::load(Path::new(file)) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!(
+                "reading '{}': {}",
+                highlight(file, args.json),
+                highlight(&e.to_string(), args.json)
             );
-            printerror(&msg);
+            printerror("io_error", &msg, file, None, args.json);
+            std::process::exit(1);
         }
-        nix_editor::read::ReadError::ArrayError => {
-            msg = format!(
-                "cannot read array '{}' : {}",
-                attr.purple(),
-                "Is this value an array?".purple()
+    };
+    // A missing file is reported when it is read for the command itself
+    let f = fs::read_to_string(&args.file).unwrap_or_default();
+    if let Err(e) = packages.checkitems(&f, attribute, &arr) {
+        printliberr(Error::from(e), &args.file, args.json);
+        std::process::exit(1);
+    }
+}
+
+fn printoutput(args: &Args, out: &str) {
+    if let Some(file) = &args.output {
+        if let Err(e) = writetofile(file, out) {
+            writeerr(file, &e, args.json);
+            std::process::exit(1);
+        }
+    } else {
+        println!("{}", out);
+    }
+}
+
+// Print an error about the attribute given on the command line. Syntax errors in
+// the file do not know which attribute was asked for.
+fn attrerr(e: Error, file: &str, attr: &str, json: bool) {
+    if e.attr.is_none() && !attr.is_empty() {
+        printliberr(e.withattr(attr), file, json);
+    } else {
+        printliberr(e, file, json);
+    }
+}
+
+// Print an error from the library with the part of the file it is about. Errors
+// that do not know their file happened in `file`.
+fn printliberr(e: Error, file: &str, json: bool) {
+    let e = match &e.file {
+        Some(_) => e,
+        None => e.withfile(Path::new(file)),
+    };
+    let path = e.file.clone().unwrap_or_default();
+    let source = fs::read_to_string(&path).unwrap_or_default();
+    let e = if e.span.is_none() { e.locate(&source) } else { e };
+    if json {
+        let err = json!({
+            "error": {
+                "code": e.code(),
+                "message": e.to_string(),
+                "file": path,
+                "attr": e.attr,
+                "span": e.span.as_deref().map(jsonlocation),
+                "label": e.label(),
+                "prefix": e.prefix.as_ref().map(|x| x.to_string()),
+                "missing": e.missing(),
+            }
+        });
+        eprintln!("{}", err);
+    } else {
+        let out = e.render(&source);
+        eprintln!("{}{}", "error:".red(), out.strip_prefix("error:").unwrap_or(&out));
+    }
+}
+
+fn nofileerr(file: &str, json: bool) {
+    let msg = format!(
+        "reading '{}': {}",
+        highlight(file, json),
+        highlight("No such file", json)
+    );
+    printerror("no_file", &msg, file, None, json);
+}
+
+fn highlight(s: &str, json: bool) -> String {
+    if json {
+        s.to_string()
+    } else {
+        s.purple().to_string()
+    }
+}
+
+// Report the elements `list-add --ensure` added, on stderr so the output stays the file
+fn printadded(added: &[String], json: bool) {
+    if json {
+        eprintln!("{}", json!({ "added": added }));
+    } else {
+        for item in added {
+            eprintln!("{} {}", "added".green(), item);
+        }
+    }
+}
+
+// Report the elements `list-remove` did not find, on stderr like `printadded`
+fn printmissing(missing: &[String], json: bool) {
+    if json {
+        eprintln!("{}", json!({ "missing": missing }));
+    } else {
+        for item in missing {
+            eprintln!("{} {}", "not found".yellow(), item);
+        }
+    }
+}
+
+// Warn about imported files the workspace could not parse, on stderr
+fn printskipped(skipped: &[Error], json: bool) {
+    for e in skipped {
+        let file = e.file.clone().unwrap_or_default();
+        let at = match &e.span {
+            Some(x) => format!("{}:{}:{}", file.display(), x.line, x.column),
+            None => file.display().to_string(),
+        };
+        if json {
+            let out = json!({
+                "skipped": {
+                    "code": e.code(),
+                    "message": e.to_string(),
+                    "file": file,
+                    "span": e.span.as_deref().map(jsonlocation),
+                }
+            });
+            eprintln!("{}", out);
+        } else {
+            eprintln!(
+                "{} skipped '{}', its imports are not followed: {}",
+                "warning:".yellow(),
+                highlight(&at, json),
+                e
             );
-            printerror(&msg);
         }
     }
 }
 
-fn nofileerr(file: &str) {
-    let msg = format!("reading '{}': {}", file.purple(), "No such file".purple());
-    printerror(&msg);
+// Print an error about writing the output to `file`
+fn writeerr(file: &str, e: &io::Error, json: bool) {
+    let msg = format!(
+        "writing '{}': {}",
+        highlight(file, json),
+        highlight(&e.to_string(), json)
+    );
+    printerror("io_error", &msg, file, None, json);
 }
 
-fn printerror(msg: &str) {
-    println!("{} {}", "error:".red(), msg);
+fn printerror(code: &str, msg: &str, file: &str, attr: Option<&str>, json: bool) {
+    if json {
+        let err = json!({
+            "error": {
+                "code": code,
+                "message": msg,
+                "file": file,
+                "attr": attr,
+            }
+        });
+        eprintln!("{}", err);
+    } else {
+        eprintln!("{} {}", "error:".red(), msg);
+    }
 }
 
 
 fn main() {
     let args = Args::parse();
-    let output;
     if !Path::is_file(Path::new(&args.file)) {
-        nofileerr(&args.file);
+        nofileerr(&args.file, args.json);
         std::process::exit(1);
     }
     let f = fs::read_to_string(&args.file).expect("Failed to read file");
 
-    if args.arr.is_some() {
-        output = match addtoarr(&f, &args.attribute, vec![args.arr.unwrap()]) {
+    if args.in_place
+        && matches!(
+            args.command,
+            Command::Get { .. } | Command::List { .. } | Command::Keys { .. } | Command::Where { .. }
+        )
+    {
+        let msg = format!(
+            "cannot edit '{}' in place : {}",
+            highlight(&args.file, args.json),
+            highlight("Command does not modify the file", args.json)
+        );
+        printerror("read_in_place", &msg, &args.file, None, args.json);
+        std::process::exit(1);
+    }
+
+    checkoptions(&args);
+    checkpackages(&args);
+
+    if args.imports || matches!(args.command, Command::Where { .. }) {
+        runimports(&args, &f);
+        return;
+    }
+
+    // Reported once the output is written, as nothing is added if that fails
+    let mut added = None;
+    let mut missing = None;
+    let output = match &args.command {
+        Command::Get { attribute } => match printread(&f, attribute, args.json) {
             Ok(x) => x,
             Err(e) => {
-                writeerr(e, &args.file, &args.attribute);
+                attrerr(e, &args.file, attribute, args.json);
                 std::process::exit(1)
             }
-        };
-    }
-
-    else if args.val.is_some() {
-        output = match write(&f, &args.attribute, &args.val.unwrap()) {
+        },
+        Command::Set { attribute, value, string, comment, inner } => match setvalue(&f, attribute, value, *string, *inner, comment.as_deref()) {
             Ok(x) => x,
             Err(e) => {
-                writeerr(e, &args.file, &args.attribute);
+                attrerr(e, &args.file, attribute, args.json);
                 std::process::exit(1)
             }
-        };
-    } else if args.deref {
-        output = match deref(&f, &args.attribute) {
+        },
+        Command::Unset { attribute } => match deref(&f, attribute) {
             Ok(x) => x,
             Err(e) => {
-                writeerr(e, &args.file, &args.attribute);
+                attrerr(e, &args.file, attribute, args.json);
                 std::process::exit(1)
             }
-        };
-    } else {
-        output = match printread(&f, &args.attribute) {
+        },
+        Command::ListAdd { attribute, arr, index, before, after, sorted, ensure: true } => match ensurearr(
+            &f,
+            attribute,
+            arr.clone(),
+            &getpositionarg(*index, before, after, *sorted),
+        ) {
+            Ok((x, items)) => {
+                added = Some(items);
+                x
+            }
+            Err(e) => {
+                attrerr(e, &args.file, attribute, args.json);
+                std::process::exit(1)
+            }
+        },
+        Command::ListAdd { attribute, arr, index, before, after, sorted, .. } => match insertarr(
+            &f,
+            attribute,
+            arr.clone(),
+            &getpositionarg(*index, before, after, *sorted),
+        ) {
             Ok(x) => x,
             Err(e) => {
-                readerr(e, &args.file, &args.attribute);
+                attrerr(e, &args.file, attribute, args.json);
                 std::process::exit(1)
             }
-        };
-    }
+        },
+        Command::ListRemove { attribute, arr, strict } => match removearr(&f, attribute, arr.clone(), *strict) {
+            Ok((x, items)) => {
+                missing = Some(items);
+                x
+            }
+            Err(e) => {
+                attrerr(e, &args.file, attribute, args.json);
+                std::process::exit(1)
+            }
+        },
+        Command::ListReplace { attribute, old, new } => match replacearr(&f, attribute, old, new) {
+            Ok(x) => x,
+            Err(e) => {
+                attrerr(e, &args.file, attribute, args.json);
+                std::process::exit(1)
+            }
+        },
+        Command::Wrap { attribute, wrapper, priority } => {
+            match addwrapper(&f, attribute, &getwrapperarg(wrapper, *priority)) {
+                Ok(x) => x,
+                Err(e) => {
+                    attrerr(e, &args.file, attribute, args.json);
+                    std::process::exit(1)
+                }
+            }
+        }
+        Command::Unwrap { attribute } => match rmwrapper(&f, attribute) {
+            Ok(x) => x,
+            Err(e) => {
+                attrerr(e, &args.file, attribute, args.json);
+                std::process::exit(1)
+            }
+        },
+        Command::List { attribute } => match printlist(&f, attribute, args.json) {
+            Ok(x) => x,
+            Err(e) => {
+                attrerr(e, &args.file, attribute, args.json);
+                std::process::exit(1)
+            }
+        },
+        Command::Keys { attribute } => match printkeys(&f, attribute.as_deref(), args.json) {
+            Ok(x) => x,
+            Err(e) => {
+                attrerr(e, &args.file, attribute.as_deref().unwrap_or_default(), args.json);
+                std::process::exit(1)
+            }
+        },
+        // Always follows imports
+        Command::Where { .. } => unreachable!(),
+    };
 
-    if args.output.is_some() {
-        writetofile(&args.output.unwrap(), &output)
+    if args.in_place {
+        if let Err(e) = writeinplace(&args.file, &output, args.backup.as_deref()) {
+            writeerr(&args.file, &e, args.json);
+            std::process::exit(1);
+        }
     } else {
-        println!("{}", output);
+        printoutput(&args, &output);
+    }
+    if let Some(x) = added {
+        printadded(&x, args.json);
+    }
+    if let Some(x) = missing {
+        printmissing(&x, args.json);
     }
 }
@@ -0,0 +1,432 @@
+use std::{fmt, fs, io, path::Path};
+
+use crate::{
+    parse::{parsechecked, AttrPath},
+    value::NixValue,
+    wrapper::unwrap,
+};
+
+// An option declared in NixOS, as listed in the `options.json` built with the manual
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NixOption {
+    pub name: String,
+    // Description of the type, like `boolean` or `list of string`
+    pub typename: String,
+    pub description: Option<String>,
+    pub readonly: bool,
+}
+
+// Why a value can not be written to an attribute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    // No option has this name. The suggestions are the closest option names.
+    UnknownOption { attr: String, suggestions: Vec<String> },
+    WrongType { attr: String, expected: String, value: String },
+    ReadOnly { attr: String },
+    // A package added to the list `attr` is not in the package index
+    UnknownPackage { attr: String, package: String, suggestions: Vec<String> },
+}
+
+impl ValidationError {
+    // The attribute that failed, which is inside the written one if a set was written
+    pub fn attr(&self) -> &str {
+        match self {
+            ValidationError::UnknownOption { attr, .. } => attr,
+            ValidationError::WrongType { attr, .. } => attr,
+            ValidationError::ReadOnly { attr } => attr,
+            ValidationError::UnknownPackage { attr, .. } => attr,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UnknownOption { attr, suggestions } => {
+                write!(f, "no option `{}`", attr)?;
+                match suggestions.len() {
+                    0 => Ok(()),
+                    1 => write!(f, ", did you mean `{}`?", suggestions[0]),
+                    _ => write!(f, ", did you mean one of `{}`?", suggestions.join("`, `")),
+                }
+            }
+            ValidationError::WrongType { attr, expected, value } => {
+                write!(f, "`{}` must be {}, not `{}`", attr, expected, value)
+            }
+            ValidationError::ReadOnly { attr } => write!(f, "`{}` is read-only", attr),
+            ValidationError::UnknownPackage { package, suggestions, .. } => {
+                write!(f, "no package `{}`", package)?;
+                match suggestions.len() {
+                    0 => Ok(()),
+                    1 => write!(f, ", did you mean `{}`?", suggestions[0]),
+                    _ => write!(f, ", did you mean one of `{}`?", suggestions.join("`, `")),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// The options of a NixOS system, for checking attributes and values before they
+// are written
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    // Option names split into segments, where `<name>` matches any name and `*`
+    // stands for the elements of a list
+    options: Vec<(Vec<String>, NixOption)>,
+}
+
+impl Options {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let f = fs::read_to_string(path)?;
+        match Options::from_json(&f) {
+            Ok(x) => Ok(x),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    pub fn from_json(f: &str) -> Result<Self, serde_json::Error> {
+        let json: serde_json::Value = serde_json::from_str(f)?;
+        let mut options = vec![];
+        if let Some(map) = json.as_object() {
+            for (name, v) in map {
+                // Descriptions are either plain strings or `{ "_type": ..., "text": ... }`
+                let description = match &v["description"] {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    x => x["text"].as_str().map(|s| s.to_string()),
+                };
+                let option = NixOption {
+                    name: name.clone(),
+                    typename: v["type"].as_str().unwrap_or_default().to_string(),
+                    description,
+                    readonly: v["readOnly"].as_bool().unwrap_or_default(),
+                };
+                options.push((AttrPath::parse(name).segments().to_vec(), option));
+            }
+        }
+        options.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        Ok(Options { options })
+    }
+
+    pub fn len(&self) -> usize {
+        self.options.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    // The option declaring `attr`, matching names given to `<name>` placeholders
+    pub fn find(&self, attr: &str) -> Option<&NixOption> {
+        self.findpath(AttrPath::parse(attr).segments())
+    }
+
+    // Check the Nix source `val` before writing it to `attr`. Values that need to be
+    // evaluated, like `pkgs.hello` or `mkIf`, are only checked as far as possible.
+    pub fn check(&self, attr: &str, val: &str) -> Result<(), ValidationError> {
+        let value = match parsechecked(val).ok().and_then(|x| x.first_child()) {
+            Some(x) => NixValue::from_node(&unwrap(&x).0),
+            None => NixValue::Expr(val.to_string()),
+        };
+        self.checkvalue(attr, &value)
+    }
+
+    pub fn checkvalue(&self, attr: &str, value: &NixValue) -> Result<(), ValidationError> {
+        self.checkpath(AttrPath::parse(attr).segments(), value)
+    }
+
+    // The closest option names to `attr`, best first
+    pub fn suggest(&self, attr: &str) -> Vec<String> {
+        let path = AttrPath::parse(attr).segments().to_vec();
+        // Look for the misspelled segment after the longest part that does exist
+        let n = (0..path.len())
+            .rev()
+            .find(|n| self.hasdescendants(&path[..*n]))
+            .unwrap_or_default();
+        let mut names = self
+            .options
+            .iter()
+            .filter(|(p, _)| p.len() > n && matches(&p[..n], &path[..n]))
+            .map(|(p, _)| p[n].clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        let segment = &path[n];
+        let limit = (segment.chars().count() / 3).max(1);
+        let mut close = names
+            .into_iter()
+            .map(|x| (distance(segment, &x), x))
+            .filter(|(d, _)| *d <= limit)
+            .collect::<Vec<_>>();
+        close.sort();
+        close
+            .into_iter()
+            .take(3)
+            .map(|(_, x)| {
+                let mut out = path.clone();
+                out[n] = x;
+                AttrPath::new(out).to_string()
+            })
+            .collect()
+    }
+
+    fn findpath(&self, path: &[String]) -> Option<&NixOption> {
+        self.options
+            .iter()
+            .find(|(p, _)| matches(p, path))
+            .map(|(_, o)| o)
+    }
+
+    // Whether options are declared below `path`
+    fn hasdescendants(&self, path: &[String]) -> bool {
+        self.options
+            .iter()
+            .any(|(p, _)| p.len() > path.len() && matches(&p[..path.len()], path))
+    }
+
+    fn checkpath(&self, path: &[String], value: &NixValue) -> Result<(), ValidationError> {
+        let attr = AttrPath::new(path.to_vec()).to_string();
+        let option = self.findpath(path);
+        if let Some(o) = option {
+            if o.readonly {
+                return Err(ValidationError::ReadOnly { attr });
+            }
+            if typematches(&o.typename, value) == Some(false) {
+                return Err(ValidationError::WrongType {
+                    attr,
+                    expected: o.typename.clone(),
+                    value: shown(value),
+                });
+            }
+        }
+        // Sets of suboptions and lists of submodules are checked element by element
+        if let NixValue::AttrSet(attrs) = value {
+            if self.hasdescendants(path) {
+                for (k, v) in attrs {
+                    let mut sub = path.to_vec();
+                    sub.push(k.clone());
+                    self.checkpath(&sub, v)?;
+                }
+                return Ok(());
+            }
+        }
+        if let NixValue::List(items) = value {
+            let mut sub = path.to_vec();
+            sub.push("*".to_string());
+            if self.hasdescendants(&sub) {
+                for v in items {
+                    self.checkpath(&sub, v)?;
+                }
+            }
+        }
+        if option.is_some() {
+            return Ok(());
+        }
+        if self.hasdescendants(path) {
+            return match value {
+                NixValue::AttrSet(_) | NixValue::Expr(_) => Ok(()),
+                _ => Err(ValidationError::WrongType {
+                    attr,
+                    expected: "an attribute set of options".to_string(),
+                    value: shown(value),
+                }),
+            };
+        }
+        if self.isfreeform(path) {
+            return Ok(());
+        }
+        Err(ValidationError::UnknownOption {
+            suggestions: self.suggest(&attr),
+            attr,
+        })
+    }
+
+    // Whether `path` is a name inside an option that takes any attributes, like
+    // `boot.kernel.sysctl` or the `settings` of many services
+    fn isfreeform(&self, path: &[String]) -> bool {
+        for n in (1..path.len()).rev() {
+            if let Some(o) = self.findpath(&path[..n]) {
+                return !self.hasdescendants(&path[..n]) || o.typename.starts_with("open submodule");
+            }
+        }
+        false
+    }
+}
+
+fn matches(pattern: &[String], path: &[String]) -> bool {
+    pattern.len() == path.len()
+        && pattern
+            .iter()
+            .zip(path)
+            .all(|(p, s)| p == s || (p.starts_with('<') && p.ends_with('>') && s != "*"))
+}
+
+// The value as written in errors. Floats Nix has no literal for are shown as Rust prints them.
+fn shown(value: &NixValue) -> String {
+    value.to_nix().unwrap_or_else(|_| format!("{:?}", value))
+}
+
+// Whether `value` has the type described by `typename`, or None if that can not be
+// known without evaluating it or the type is not understood
+fn typematches(typename: &str, value: &NixValue) -> Option<bool> {
+    if let NixValue::Expr(_) = value {
+        return None;
+    }
+    let typename = typename.trim();
+    if let Some(inner) = typename.strip_prefix('(').and_then(|x| x.strip_suffix(')')) {
+        if balanced(inner) {
+            return typematches(inner, value);
+        }
+    }
+    let alternatives = splittop(typename, " or ");
+    if alternatives.len() > 1 {
+        let results = alternatives
+            .iter()
+            .map(|x| typematches(x, value))
+            .collect::<Vec<_>>();
+        if results.contains(&Some(true)) {
+            return Some(true);
+        }
+        if results.contains(&None) {
+            return None;
+        }
+        return Some(false);
+    }
+    if let Some(elem) = typename.strip_prefix("list of ") {
+        return match value {
+            NixValue::List(items) => all(items.iter().map(|x| typematches(elem, x))),
+            _ => Some(false),
+        };
+    }
+    let attrsof = typename
+        .strip_prefix("attribute set of ")
+        .or_else(|| typename.strip_prefix("lazy attribute set of "));
+    if let Some(elem) = attrsof {
+        return match value {
+            NixValue::AttrSet(attrs) => all(attrs.iter().map(|(_, x)| typematches(elem, x))),
+            _ => Some(false),
+        };
+    }
+    if let Some(choices) = typename.strip_prefix("one of ") {
+        let choices = splittop(choices, ", ");
+        return Some(value.to_nix().is_ok_and(|x| choices.contains(&x.as_str())));
+    }
+    if typename.contains("integer") {
+        let i = match value {
+            NixValue::Int(i) => *i,
+            _ => return Some(false),
+        };
+        return Some(inrange(typename, i));
+    }
+    let ok = match typename {
+        "null" => matches!(value, NixValue::Null),
+        "boolean" => matches!(value, NixValue::Bool(_)),
+        "floating point number" => matches!(value, NixValue::Float(_) | NixValue::Int(_)),
+        "path" | "absolute path" => matches!(value, NixValue::Path(_) | NixValue::String(_)),
+        // Packages always come from evaluating something
+        "package" => false,
+        "submodule" | "attribute set" => matches!(value, NixValue::AttrSet(_)),
+        t if t.starts_with("open submodule") => matches!(value, NixValue::AttrSet(_)),
+        t if t == "string" || t == "lines" || t.starts_with("string") || t.ends_with(" string") || t.starts_with("strings concatenated") => {
+            matches!(value, NixValue::String(_) | NixValue::Path(_))
+        }
+        _ => return None,
+    };
+    Some(ok)
+}
+
+fn all(results: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    let mut out = Some(true);
+    for r in results {
+        match r {
+            Some(false) => return Some(false),
+            None => out = None,
+            Some(true) => {}
+        }
+    }
+    out
+}
+
+// Bounds given in integer types, like `16 bit unsigned integer; between 0 and 65535
+// (both inclusive)` or `positive integer, meaning >0`
+fn inrange(typename: &str, i: i64) -> bool {
+    let unsigned = typename.contains("unsigned") || typename.contains(">=0");
+    let positive = typename.starts_with("positive") || typename.contains(">0");
+    if (unsigned && i < 0) || (positive && !unsigned && i <= 0) {
+        return false;
+    }
+    if let Some(rest) = typename.split("between ").nth(1) {
+        let bounds = rest
+            .split(|c: char| !(c.is_ascii_digit() || c == '-'))
+            .filter_map(|x| x.parse::<i64>().ok())
+            .collect::<Vec<_>>();
+        if let [low, high, ..] = bounds[..] {
+            return low <= i && i <= high;
+        }
+    }
+    true
+}
+
+// Split on `sep` outside of parenthesis and quotes
+fn splittop<'a>(s: &'a str, sep: &str) -> Vec<&'a str> {
+    let mut out = vec![];
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    let mut i = 0;
+    while i < s.len() {
+        let rest = &s[i..];
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ if depth == 0 && !quoted && rest.starts_with(sep) => {
+                out.push(&s[start..i]);
+                i += sep.len();
+                start = i;
+                continue;
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    out.push(&s[start..]);
+    out
+}
+
+fn balanced(s: &str) -> bool {
+    let mut depth = 0;
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return false,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+// Edit distance between two names, where swapping two neighbouring characters
+// counts as one edit
+pub(crate) fn distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 0..a.len() {
+        let mut cur = vec![i + 1];
+        for j in 0..b.len() {
+            let cost = if a[i] == b[j] { 0 } else { 1 };
+            let mut d = (rows[i][j] + cost).min(rows[i][j + 1] + 1).min(cur[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                d = d.min(rows[i - 1][j - 1] + 1);
+            }
+            cur.push(d);
+        }
+        rows.push(cur);
+    }
+    rows[a.len()][b.len()]
+}
@@ -0,0 +1,154 @@
+use std::{fs, io, path::Path};
+
+use rnix::SyntaxKind;
+
+use crate::{
+    options::{distance, ValidationError},
+    parse::{parsechecked, AttrPath},
+    read::getwithvalue,
+};
+
+// The names packages are usually reached through in a configuration
+const SCOPES: [&str; 1] = ["pkgs"];
+
+// Channels `nix-env -qa --json` prefixes names with, as in `nixpkgs.git`
+const CHANNELS: [&str; 2] = ["nixpkgs", "nixos"];
+
+// The attribute names of the packages in nixpkgs, as listed by `nix-env -qa --json`
+#[derive(Clone, Debug, Default)]
+pub struct Packages {
+    names: Vec<String>,
+}
+
+impl Packages {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let f = fs::read_to_string(path)?;
+        match Packages::from_json(&f) {
+            Ok(x) => Ok(x),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    pub fn from_json(f: &str) -> Result<Self, serde_json::Error> {
+        let json: serde_json::Value = serde_json::from_str(f)?;
+        let mut names = match json.as_object() {
+            Some(map) => map
+                .keys()
+                .map(|x| AttrPath::new(stripchannel(AttrPath::parse(x).segments()).to_vec()).to_string())
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        names.sort();
+        names.dedup();
+        Ok(Packages { names })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.binary_search(&name.to_string()).is_ok()
+    }
+
+    // Check the Nix source `item` before adding it to the list `attr`. `withvals` are
+    // the scopes the list is under, as returned by `getwithvalue`. Only names of
+    // packages are checked, other expressions and variables that are not from a
+    // package scope pass.
+    pub fn check(&self, attr: &str, item: &str, withvals: &[String]) -> Result<(), ValidationError> {
+        let path = match packagepath(item) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let segments = path.segments();
+        let (scope, name) = if SCOPES.contains(&segments[0].as_str()) && segments.len() > 1 {
+            (Some(segments[0].as_str()), path.skip(1))
+        } else if withvals.iter().any(|x| SCOPES.contains(&x.as_str())) {
+            (None, path.clone())
+        } else {
+            return Ok(());
+        };
+        let name = name.to_string();
+        if self.contains(&name) {
+            return Ok(());
+        }
+        let suggestions = self
+            .suggest(&name)
+            .into_iter()
+            .map(|x| match scope {
+                Some(s) => format!("{}.{}", s, x),
+                None => x,
+            })
+            .collect();
+        Err(ValidationError::UnknownPackage {
+            attr: attr.to_string(),
+            package: item.trim().to_string(),
+            suggestions,
+        })
+    }
+
+    // Check all `items` before adding them to the list `query` in the file `f`
+    pub fn checkitems(&self, f: &str, query: &str, items: &[String]) -> Result<(), ValidationError> {
+        let withvals = getwithvalue(f, query).unwrap_or_default();
+        for item in items {
+            self.check(query, item, &withvals)?;
+        }
+        Ok(())
+    }
+
+    // The closest package names to `name`, best first. Only names in the same set,
+    // like `python3Packages`, are compared.
+    pub fn suggest(&self, name: &str) -> Vec<String> {
+        let path = AttrPath::parse(name);
+        let parent = path.parent();
+        let last = match path.segments().last() {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let limit = (last.chars().count() / 3).max(1);
+        let mut close = self
+            .names
+            .iter()
+            .map(|x| AttrPath::parse(x))
+            .filter(|x| x.parent() == parent)
+            .filter_map(|x| {
+                let d = distance(last, x.segments().last()?);
+                Some((d, x.to_string()))
+            })
+            .filter(|(d, _)| *d <= limit)
+            .collect::<Vec<_>>();
+        close.sort();
+        close.into_iter().take(3).map(|(_, x)| x).collect()
+    }
+}
+
+// The attribute path `item` refers to, if it is a plain name like `git` or
+// `pkgs.python3Packages.requests`
+fn packagepath(item: &str) -> Option<AttrPath> {
+    let node = parsechecked(item).ok()?.first_child()?;
+    if !matches!(node.kind(), SyntaxKind::NODE_IDENT | SyntaxKind::NODE_SELECT) {
+        return None;
+    }
+    // `a.${b}` and `a.b or c` can not be looked up without evaluating them
+    if node
+        .descendants()
+        .any(|x| matches!(x.kind(), SyntaxKind::NODE_DYNAMIC | SyntaxKind::NODE_OR_DEFAULT))
+    {
+        return None;
+    }
+    Some(AttrPath::parse(item.trim()))
+}
+
+// Drop the channel, or the `legacyPackages.<system>` of `nix search --json`, from the
+// name of a package
+fn stripchannel(path: &[String]) -> &[String] {
+    match path {
+        [first, _, rest @ ..] if first == "legacyPackages" && !rest.is_empty() => rest,
+        [first, rest @ ..] if CHANNELS.contains(&first.as_str()) && !rest.is_empty() => rest,
+        _ => path,
+    }
+}
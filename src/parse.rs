@@ -1,22 +1,257 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
 
-use rnix::{self, SyntaxKind, SyntaxNode};
+use rnix::{
+    self,
+    types::{Str, TypedNode},
+    StrPart, SyntaxKind, SyntaxNode, TextRange, TextSize,
+};
 
-use crate::read::ReadError;
+use crate::{
+    errors::Error,
+    location::Location,
+    wrapper::{attrsets, unwrap, Wrapper},
+};
+
+// An attribute path such as `services."nginx".enable`, stored as unquoted segments
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AttrPath(Vec<String>);
+
+impl AttrPath {
+    pub fn new(segments: Vec<String>) -> Self {
+        AttrPath(segments)
+    }
+
+    // Parse a query string, splitting on dots that are not inside double quotes
+    pub fn parse(query: &str) -> Self {
+        let mut segments = vec![];
+        let mut current = String::new();
+        let mut chars = query.chars();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => {
+                    if let Some(next) = chars.next() {
+                        current.push(next);
+                    }
+                }
+                '.' if !quoted => segments.push(std::mem::take(&mut current)),
+                _ => current.push(c),
+            }
+        }
+        segments.push(current);
+        AttrPath(segments)
+    }
+
+    // Read the path of a NODE_KEY, unquoting string segments
+    pub fn from_key(node: &SyntaxNode) -> Self {
+        let mut segments = vec![];
+        for child in node.children() {
+            match child.kind() {
+                SyntaxKind::NODE_IDENT => segments.push(child.text().to_string()),
+                SyntaxKind::NODE_STRING => segments.push(unquote(&child)),
+                // Dynamic keys can only be matched by their source text
+                _ => segments.push(child.to_string()),
+            }
+        }
+        AttrPath(segments)
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn starts_with(&self, prefix: &AttrPath) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+
+    // The path with the first `n` segments removed
+    pub fn skip(&self, n: usize) -> AttrPath {
+        AttrPath(self.0[n.min(self.0.len())..].to_vec())
+    }
+
+    // The path without its last segment
+    pub fn parent(&self) -> AttrPath {
+        AttrPath(self.0[..self.0.len().saturating_sub(1)].to_vec())
+    }
+
+    pub fn join(&self, other: &AttrPath) -> AttrPath {
+        let mut segments = self.0.clone();
+        segments.extend(other.0.iter().cloned());
+        AttrPath(segments)
+    }
+}
+
+// Parse the query for an attribute to write, which needs a name for every segment
+// and its quotes closed
+pub fn parsequery(query: &str) -> Result<AttrPath, String> {
+    let mut quoted = false;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err("a quote is not closed".to_string());
+    }
+    let path = AttrPath::parse(query);
+    if path.segments().iter().any(|x| x.is_empty()) {
+        return Err("a segment is empty".to_string());
+    }
+    Ok(path)
+}
+
+impl From<&str> for AttrPath {
+    fn from(query: &str) -> Self {
+        AttrPath::parse(query)
+    }
+}
+
+impl fmt::Display for AttrPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let segments = self.0.iter().map(|s| quotesegment(s)).collect::<Vec<_>>();
+        write!(f, "{}", segments.join("."))
+    }
+}
+
+// A syntax error reported by rnix, and where in the parsed text it is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub location: Location,
+}
+
+// Why a file or value could not be used. Without syntax errors the text is valid
+// Nix, but does not contain an attribute set to work on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParseError {
+    pub errors: Vec<SyntaxError>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "no attribute set found");
+        }
+        let errors = self
+            .errors
+            .iter()
+            .map(|e| format!("{}:{}: {}", e.location.line, e.location.column, e.message))
+            .collect::<Vec<_>>();
+        write!(f, "{}", errors.join(", "))
+    }
+}
+
+// Parse `f`, failing with every syntax error rnix reports
+pub fn parsechecked(f: &str) -> Result<SyntaxNode, ParseError> {
+    let ast = rnix::parse(f);
+    let errors: Vec<SyntaxError> = ast
+        .errors()
+        .iter()
+        .map(|e| {
+            let range = match e {
+                rnix::parser::ParseError::Unexpected(r)
+                | rnix::parser::ParseError::UnexpectedExtra(r)
+                | rnix::parser::ParseError::UnexpectedWanted(_, r, _)
+                | rnix::parser::ParseError::UnexpectedDoubleBind(r)
+                | rnix::parser::ParseError::DuplicatedArgs(r, _) => *r,
+                // Errors at the end of the input have no range of their own
+                _ => TextRange::empty(TextSize::of(f)),
+            };
+            SyntaxError {
+                message: e.to_string(),
+                location: Location::new(f, range),
+            }
+        })
+        .fold(Vec::new(), |mut out, e| {
+            // The same error can be reported by both the parser and the tree
+            if !out.contains(&e) {
+                out.push(e);
+            }
+            out
+        });
+    if errors.is_empty() {
+        Ok(ast.node())
+    } else {
+        Err(ParseError { errors })
+    }
+}
+
+// Parse a configuration file and find the attribute set it returns
+pub fn parseconfig(f: &str) -> Result<SyntaxNode, ParseError> {
+    match getcfgbase(&parsechecked(f)?) {
+        Some(x) => Ok(x),
+        None => Err(ParseError::default()),
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "rec", "then", "with",
+];
+
+// Quote a path segment if it can not be written as a plain identifier
+pub fn quotesegment(segment: &str) -> String {
+    let mut chars = segment.chars();
+    let isident = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-'),
+        _ => false,
+    };
+    // Dynamic keys are kept as they were written
+    if (isident && !KEYWORDS.contains(&segment)) || segment.starts_with("${") {
+        segment.to_string()
+    } else {
+        format!(
+            "\"{}\"",
+            segment
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace("${", "\\${")
+        )
+    }
+}
+
+fn unquote(node: &SyntaxNode) -> String {
+    let mut out = String::new();
+    if let Some(s) = Str::cast(node.clone()) {
+        for part in s.parts() {
+            match part {
+                StrPart::Literal(l) => out.push_str(&l),
+                // Interpolated keys can only be matched by their source text
+                StrPart::Ast(_) => return node.to_string(),
+            }
+        }
+    }
+    out
+}
 
 pub fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
-    let mut childvec: Vec<(String, String)> = Vec::new();
+    // The bindings of the set, as `key = value` without the semicolon
+    let mut childvec: Vec<String> = Vec::new();
     for child in configbase.children() {
         if child.kind() == SyntaxKind::NODE_KEY_VALUE {
             // Now we have to read all the indent values from the key
             for subchild in child.children() {
                 if subchild.kind() == SyntaxKind::NODE_KEY {
                     // We have a key, now we need to check if it's the one we're looking for
-                    let key = getkey(&subchild);
-                    let qkey = name
-                        .split('.')
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
+                    let key = AttrPath::from_key(&subchild);
+                    let qkey = AttrPath::parse(name);
                     if qkey == key {
                         if child
                             .children()
@@ -25,15 +260,23 @@ pub fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
                             if let Some(x) = child.children().last() {
                                 if x.kind() == SyntaxKind::NODE_ATTR_SET {
                                     for n in x.children() {
+                                        // `inherit` has no value to split off
+                                        if n.kind() == SyntaxKind::NODE_INHERIT {
+                                            let s = n.to_string();
+                                            childvec.push(s.trim_end_matches(';').to_string());
+                                            continue;
+                                        }
                                         let i = n.children().count();
-                                        if let (Some(k), Some(v)) =
-                                            (n.children().nth(i - 2), n.last_child())
-                                        {
-                                            let f = n.to_string().find(&k.to_string()).unwrap()
+                                        if let (Some(k), Some(v)) = (
+                                            i.checked_sub(2).and_then(|i| n.children().nth(i)),
+                                            n.last_child(),
+                                        ) {
+                                            let f = n.to_string().find(&k.to_string())?
                                                 + k.to_string().len();
-                                            childvec.push((
-                                                n.to_string()[0..f].to_string(),
-                                                v.to_string(),
+                                            childvec.push(format!(
+                                                "{} = {}",
+                                                &n.to_string()[0..f],
+                                                v
                                             ));
                                         }
                                     }
@@ -44,21 +287,21 @@ pub fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
                         }
                     } else if qkey.len() > key.len() {
                         // We have a subkey, so we need to recurse
-                        if key == qkey[0..key.len()] {
+                        if qkey.starts_with(&key) {
                             // We have a subkey, so we need to recurse
-                            let subkey = &qkey[key.len()..].join(".").to_string();
-                            if let Some(newbase) = getcfgbase(&child) {
-                                if let Some(subattr) = findattr(&newbase, subkey) {
-                                    return Some(subattr);
+                            let subkey = qkey.skip(key.len()).to_string();
+                            // Look inside `mkIf` and every set given to `mkMerge`
+                            if let Some(value) = child.last_child() {
+                                for newbase in attrsets(&value) {
+                                    if let Some(subattr) = findattr(&newbase, &subkey) {
+                                        return Some(subattr);
+                                    }
                                 }
                             }
                         }
-                    } else if qkey.len() < key.len() && qkey == key[0..qkey.len()] {
-                        match child.last_child() {
-                            Some(x) => {
-                                childvec.push((key[qkey.len()..].join("."), x.to_string()));
-                            }
-                            None => {}
+                    } else if qkey.len() < key.len() && key.starts_with(&qkey) {
+                        if let Some(x) = child.last_child() {
+                            childvec.push(format!("{} = {}", key.skip(qkey.len()), x));
                         }
                     }
                 }
@@ -68,13 +311,14 @@ pub fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
     if childvec.is_empty() {
         None
     } else {
+        let name = AttrPath::parse(name);
         let s;
         if childvec.len() == 1 {
-            s = format!("{{{} = {{ {} = {}; }}; }}", name, childvec[0].0, childvec[0].1);
+            s = format!("{{{} = {{ {}; }}; }}", name, childvec[0]);
         } else {
             let mut list = String::new();
-            for (k, v) in childvec.iter() {
-                list.push_str(&format!("  {} = {};\n", k, v));
+            for b in childvec.iter() {
+                list.push_str(&format!("  {};\n", b));
             }
             list = list.strip_suffix('\n').unwrap_or(&list).to_string();
             s = format!("{{ {} = {{\n{}\n}}; }}", name, list);
@@ -93,57 +337,213 @@ pub fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
     }
 }
 
-pub fn get_collection(f: String) -> Result<HashMap<String, String>, ReadError> {
-    let mut map = HashMap::new();
-    let ast = rnix::parse(&f);
-    let configbase = match getcfgbase(&ast.node()) {
-        Some(x) => x,
-        None => {
-            return Err(ReadError::ParseError);
+// The binding that defines `name` as it is written in the file. Unlike `findattr`
+// this does not combine bindings, so attributes that only exist as part of longer
+// keys, like `a` in `a.b = 1;`, are not found.
+pub(crate) fn findbinding(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
+    let qkey = AttrPath::parse(name);
+    for child in configbase.children() {
+        if child.kind() != SyntaxKind::NODE_KEY_VALUE {
+            continue;
         }
-    };
+        let key = match child.first_child() {
+            Some(x) if x.kind() == SyntaxKind::NODE_KEY => AttrPath::from_key(&x),
+            _ => continue,
+        };
+        if qkey == key {
+            return Some(child);
+        } else if qkey.len() > key.len() && qkey.starts_with(&key) {
+            let subkey = qkey.skip(key.len()).to_string();
+            if let Some(value) = child.last_child() {
+                for newbase in attrsets(&value) {
+                    if let Some(x) = findbinding(&newbase, &subkey) {
+                        return Some(x);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+// How far an attribute path can be followed in a file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Found,
+    // `prefix` is an attribute set without `missing`. The prefix is empty when not
+    // even the first segment exists.
+    Missing { prefix: AttrPath, missing: String },
+    // `prefix` is bound to something other than an attribute set, like `import ./x.nix`,
+    // so `missing` can not be looked up or added without evaluating it
+    NotAttrSet { prefix: AttrPath, missing: String },
+}
+
+// Follow `query` as far as it goes, finding the longest part of it that exists
+pub fn resolvepath(configbase: &SyntaxNode, query: &str) -> Resolution {
+    let path = AttrPath::parse(query);
+    if findattr(configbase, query).is_some() {
+        return Resolution::Found;
+    }
+    for n in (1..path.len()).rev() {
+        let prefix = AttrPath::new(path.segments()[..n].to_vec());
+        let value = match findattr(configbase, &prefix.to_string()).and_then(|x| x.last_child()) {
+            Some(x) => x,
+            None => continue,
+        };
+        let missing = path.segments()[n].clone();
+        if issetvalue(&value) {
+            return Resolution::Missing { prefix, missing };
+        }
+        return Resolution::NotAttrSet { prefix, missing };
+    }
+    Resolution::Missing {
+        prefix: AttrPath::default(),
+        missing: path.segments().first().cloned().unwrap_or_default(),
+    }
+}
+
+pub fn get_collection(f: String) -> Result<HashMap<String, String>, Error> {
+    let mut map = HashMap::new();
+    let configbase = parseconfig(&f)?;
     collectattrs(&configbase, &mut map);
     Ok(map)
 }
 
-pub fn collectattrs(configbase: &SyntaxNode, map: &mut HashMap<String, String>)
-/*-> HashMap<String, String>*/
-{
-    for child in configbase.children() {
-        if child.kind() == SyntaxKind::NODE_KEY_VALUE {
-            let children = child.children().collect::<Vec<SyntaxNode>>();
-            let nodekey = children.get(0).unwrap();
-            let value = children.get(1).unwrap();
-            if nodekey.kind() == SyntaxKind::NODE_KEY {
-                if value.kind() == SyntaxKind::NODE_ATTR_SET {
-                    let mut childmap = HashMap::new();
-                    collectattrs(value, &mut childmap);
-                    for (nk, v) in &childmap {
-                        map.insert(format!("{}.{}", nodekey, nk), v.clone());
+pub fn collectattrs(configbase: &SyntaxNode, map: &mut HashMap<String, String>) {
+    for (path, value, _) in attrs(configbase, None) {
+        if value.parent().map(|x| x.kind()) == Some(SyntaxKind::NODE_INHERIT) {
+            map.insert(path.to_string(), inheritvalue(&value));
+        } else if !issetvalue(&value) {
+            map.insert(path.to_string(), unwrap(&value).0.to_string());
+        }
+    }
+}
+
+// An attribute, the value bound to it and the location of its binding. The value
+// is the node as written, including any `mkIf`, `mkDefault`, ... wrappers. Names
+// brought in with `inherit` have their identifier as the value and location.
+pub type Attr = (AttrPath, SyntaxNode, Location);
+
+// Iterator over the attributes of a set in the order they are written. Nested sets,
+// also those inside wrappers and `mkMerge`, come right before their attributes.
+pub struct Attrs {
+    text: String,
+    depth: Option<usize>,
+    // Sets being walked, with the path they are bound to and how deeply they are nested
+    stack: Vec<(AttrPath, usize, std::vec::IntoIter<SyntaxNode>)>,
+    pending: VecDeque<Attr>,
+}
+
+// Walk the bindings of `configbase`. `depth` limits how many levels of nested sets
+// are entered, so with `Some(0)` only the bindings of `configbase` itself are listed.
+pub fn attrs(configbase: &SyntaxNode, depth: Option<usize>) -> Attrs {
+    let text = match configbase.ancestors().last() {
+        Some(x) => x.to_string(),
+        None => configbase.to_string(),
+    };
+    let children = configbase.children().collect::<Vec<_>>().into_iter();
+    Attrs {
+        text,
+        depth,
+        stack: vec![(AttrPath::default(), 0, children)],
+        pending: VecDeque::new(),
+    }
+}
+
+impl Iterator for Attrs {
+    type Item = Attr;
+
+    fn next(&mut self) -> Option<Attr> {
+        loop {
+            if let Some(x) = self.pending.pop_front() {
+                return Some(x);
+            }
+            let (prefix, level, children) = self.stack.last_mut()?;
+            let (prefix, level) = (prefix.clone(), *level);
+            let child = match children.next() {
+                Some(x) => x,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            match child.kind() {
+                SyntaxKind::NODE_KEY_VALUE => {
+                    let (key, value) = match (child.first_child(), child.last_child()) {
+                        (Some(k), Some(v)) if k.kind() == SyntaxKind::NODE_KEY && k != v => (k, v),
+                        _ => continue,
+                    };
+                    let path = prefix.join(&AttrPath::from_key(&key));
+                    let descend = match self.depth {
+                        Some(d) => level < d,
+                        None => true,
+                    };
+                    if issetvalue(&value) && descend {
+                        // Pushed in reverse so the sets are walked in the order they are written
+                        for set in attrsets(&value).into_iter().rev() {
+                            let children = set.children().collect::<Vec<_>>().into_iter();
+                            self.stack.push((path.clone(), level + 1, children));
+                        }
                     }
-                } else {
-                    map.insert(nodekey.to_string(), value.to_string());
+                    return Some((path, value, Location::new(&self.text, child.text_range())));
                 }
+                SyntaxKind::NODE_INHERIT => {
+                    for ident in child.children().filter(|x| x.kind() == SyntaxKind::NODE_IDENT) {
+                        let path = prefix.join(&AttrPath::new(vec![ident.to_string()]));
+                        let location = Location::new(&self.text, ident.text_range());
+                        self.pending.push_back((path, ident, location));
+                    }
+                }
+                _ => {}
             }
         }
     }
 }
 
-pub fn getkey(node: &SyntaxNode) -> Vec<String> {
-    let mut key = vec![];
-    for child in node.children() {
-        if child.kind() == SyntaxKind::NODE_IDENT {
-            key.push(child.text().to_string());
-        }
+// Whether a value is a set of attributes, possibly wrapped or merged with `mkMerge`
+fn issetvalue(value: &SyntaxNode) -> bool {
+    let (inner, wrappers) = unwrap(value);
+    inner.kind() == SyntaxKind::NODE_ATTR_SET
+        || (wrappers.last() == Some(&Wrapper::Merge) && inner.kind() == SyntaxKind::NODE_LIST)
+}
+
+// The expression an inherited name refers to, like `pkgs.foo` for `inherit (pkgs) foo;`
+fn inheritvalue(ident: &SyntaxNode) -> String {
+    let from = ident.parent().and_then(|x| {
+        x.children()
+            .find(|x| x.kind() == SyntaxKind::NODE_INHERIT_FROM)
+            .and_then(|x| x.first_child())
+    });
+    match from {
+        Some(f) => format!("{}.{}", f, ident),
+        None => ident.to_string(),
     }
-    key
+}
+
+pub fn getkey(node: &SyntaxNode) -> Vec<String> {
+    AttrPath::from_key(node).segments().to_vec()
 }
 
 pub fn getcfgbase(node: &SyntaxNode) -> Option<SyntaxNode> {
-    // First check if we're in a set
+    // First check if we're in a set, `rec` sets are the same node kind
     if node.kind() == SyntaxKind::NODE_ATTR_SET {
         return Some(node.clone());
     }
+    // Follow the expression a module actually returns, skipping over
+    // function arguments, `let` bindings and `with`/`assert` scopes
+    match node.kind() {
+        SyntaxKind::NODE_ROOT | SyntaxKind::NODE_PAREN => {
+            return node.first_child().and_then(|x| getcfgbase(&x));
+        }
+        SyntaxKind::NODE_LAMBDA
+        | SyntaxKind::NODE_LET_IN
+        | SyntaxKind::NODE_WITH
+        | SyntaxKind::NODE_ASSERT
+        | SyntaxKind::NODE_KEY_VALUE => {
+            return node.last_child().and_then(|x| getcfgbase(&x));
+        }
+        _ => {}
+    }
     // Next check if any of our children the set
     for child in node.children() {
         if child.kind() == SyntaxKind::NODE_ATTR_SET {
@@ -151,11 +551,8 @@ pub fn getcfgbase(node: &SyntaxNode) -> Option<SyntaxNode> {
         }
     }
     for child in node.children() {
-        match getcfgbase(&child) {
-            Some(x) => {
-                return Some(x);
-            }
-            None => {}
+        if let Some(x) = getcfgbase(&child) {
+            return Some(x);
         }
     }
     None
@@ -1,59 +1,104 @@
-use crate::parse::{findattr, getcfgbase};
-use failure::Fail;
+use crate::{
+    comment::{getcomments, Comments},
+    errors::{attrerror, Error, ErrorKind},
+    location::{AttrLocation, Location},
+    parse::{findattr, findbinding, getcfgbase, parsechecked, parseconfig, resolvepath, ParseError, Resolution},
+    value::{argument, NixValue},
+    wrapper::{outerwrappers, unwrap, Wrapper},
+};
 use rnix::{SyntaxKind, SyntaxNode};
 
-#[derive(Fail, Debug)]
-pub enum ReadError {
-    #[fail(display = "Read Error: Error while parsing.")]
-    ParseError,
-    #[fail(display = "Read Error: No attributes.")]
-    NoAttr,
-    #[fail(display = "Read Error: Error with array.")]
-    ArrayError,
-}
-
-pub fn readvalue(f: &str, query: &str) -> Result<String, ReadError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
-        Some(x) => x,
-        None => {
-            return Err(ReadError::ParseError);
-        }
-    };
+// Read a value as it is written, including any `mkIf`, `mkDefault`, ... wrappers.
+// `readwrapped` and `read_typed` look inside them.
+pub fn readvalue(f: &str, query: &str) -> Result<String, Error> {
+    let configbase = parseconfig(f)?;
     let outnode = match findattr(&configbase, query) {
         Some(x) => match findvalue(&x) {
             Some(y) => y.to_string(),
-            None => return Err(ReadError::NoAttr),
+            None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
         },
-        None => return Err(ReadError::NoAttr),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
     };
     Ok(outnode)
 }
 
-pub fn findvalue(node: &SyntaxNode) -> Option<SyntaxNode> {
-    // First find the IDENT node
-    for child in node.children() {
-        if child.kind() != SyntaxKind::NODE_KEY {
-            return Some(child);
-        }
+pub fn read_typed(f: &str, query: &str) -> Result<NixValue, Error> {
+    let configbase = parseconfig(f)?;
+    match findattr(&configbase, query) {
+        Some(x) => match findvalue(&x) {
+            Some(y) => Ok(NixValue::from_node(&unwrap(&y).0)),
+            None => Err(attrerror(ErrorKind::NoAttr, query, f)),
+        },
+        None => Err(attrerror(ErrorKind::NoAttr, query, f)),
     }
-    None
 }
 
-pub fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, ReadError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+// Read a value together with the `mkIf`, `mkDefault`, ... wrappers applied to it,
+// including those around the sets it is defined in
+pub fn readwrapped(f: &str, query: &str) -> Result<(String, Vec<Wrapper>), Error> {
+    let configbase = parseconfig(f)?;
+    match findattr(&configbase, query) {
+        Some(x) => match findvalue(&x) {
+            Some(y) => {
+                let (value, inner) = unwrap(&y);
+                let mut wrappers = outerwrappers(&x);
+                wrappers.extend(inner);
+                Ok((value.to_string(), wrappers))
+            }
+            None => Err(attrerror(ErrorKind::NoAttr, query, f)),
+        },
+        None => Err(attrerror(ErrorKind::NoAttr, query, f)),
+    }
+}
+
+pub fn readcomments(f: &str, query: &str) -> Result<Comments, Error> {
+    let configbase = parseconfig(f)?;
+    let root = configbase.ancestors().last();
+    match findattr(&configbase, query) {
+        // Values merged from several bindings have no comments of their own
+        Some(x) if x.ancestors().last() == root => Ok(getcomments(&x)),
+        Some(_) => Ok(Comments::default()),
+        None => Err(attrerror(ErrorKind::NoAttr, query, f)),
+    }
+}
+
+// Where the binding for `query` is in the file, for jumping to it in an editor
+pub fn readlocation(f: &str, query: &str) -> Result<AttrLocation, Error> {
+    let configbase = parseconfig(f)?;
+    let binding = match findbinding(&configbase, query) {
         Some(x) => x,
-        None => {
-            return Err(ReadError::ParseError);
-        }
+        None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
     };
+    let (key, value) = match (binding.first_child(), findvalue(&binding)) {
+        (Some(k), Some(v)) => (k, unwrap(&v).0),
+        _ => return Err(attrerror(ErrorKind::NoAttr, query, f)),
+    };
+    Ok(AttrLocation {
+        key: Location::new(f, key.text_range()),
+        value: Location::new(f, value.text_range()),
+        binding: Location::new(f, binding.text_range()),
+    })
+}
+
+// Which part of `query` exists in the file, and why the rest can not be found
+pub fn readresolution(f: &str, query: &str) -> Result<Resolution, Error> {
+    let configbase = parseconfig(f)?;
+    Ok(resolvepath(&configbase, query))
+}
+
+pub fn findvalue(node: &SyntaxNode) -> Option<SyntaxNode> {
+    // First find the IDENT node
+    node.children().find(|child| child.kind() != SyntaxKind::NODE_KEY)
+}
+
+pub fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, Error> {
+    let configbase = parseconfig(f)?;
     let output = match findattr(&configbase, query) {
         Some(x) => match getarrvals_aux(&x) {
             Some(y) => y,
-            None => return Err(ReadError::ArrayError),
+            None => return Err(attrerror(ErrorKind::Array, query, f)),
         },
-        None => return Err(ReadError::NoAttr),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
     };
     Ok(output)
 }
@@ -61,41 +106,211 @@ pub fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, ReadError> {
 fn getarrvals_aux(
     node: &SyntaxNode,
 ) -> Option<Vec<String>> {
-    for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return getarrvals_aux(&child);
+    let segments = listsegments(node);
+    if segments.is_empty() {
+        return None;
+    }
+    let mut out = vec![];
+    for (list, _, _) in segments {
+        for elem in list.children() {
+            out.push(elem.to_string());
+        }
+    }
+    Some(out)
+}
 
+// A literal list that is part of a list value, like `[ c ]` in
+// `[ a b ] ++ lib.optionals cfg.gui [ c ]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListSegment {
+    pub elems: Vec<String>,
+    // The condition of `lib.optionals` when the list is only added under one
+    pub condition: Option<String>,
+}
+
+pub fn getarrsegments(f: &str, query: &str) -> Result<Vec<ListSegment>, Error> {
+    let configbase = parseconfig(f)?;
+    let node = match findattr(&configbase, query) {
+        Some(x) => x,
+        None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
+    };
+    let segments = listsegments(&node);
+    if segments.is_empty() {
+        return Err(attrerror(ErrorKind::Array, query, f));
+    }
+    Ok(segments
+        .into_iter()
+        .map(|(list, condition, _)| ListSegment {
+            elems: list.children().map(|x| x.to_string()).collect(),
+            condition,
+        })
+        .collect())
+}
+
+// The literal lists the value of the binding `node` is concatenated from, in the
+// order they are written, with their condition and the scopes of the `with`s they
+// are under. Parts that need evaluating, like `import ./pkgs.nix`, are left out.
+pub(crate) fn listsegments(node: &SyntaxNode) -> Vec<(SyntaxNode, Option<String>, Vec<String>)> {
+    let mut out = vec![];
+    if let Some(value) = findvalue(node) {
+        listsegments_aux(&value, None, vec![], &mut out);
+    }
+    out
+}
+
+fn listsegments_aux(
+    node: &SyntaxNode,
+    condition: Option<String>,
+    mut withvals: Vec<String>,
+    out: &mut Vec<(SyntaxNode, Option<String>, Vec<String>)>,
+) {
+    let (node, wrappers) = unwrap(node);
+    // A list under `mkIf` is only there when its condition holds
+    let condition = wrappers.into_iter().fold(condition, |acc, w| match w {
+        Wrapper::If(cond) => Some(both(acc, &cond)),
+        _ => acc,
+    });
+    match node.kind() {
+        SyntaxKind::NODE_LIST => out.push((node, condition, withvals)),
+        SyntaxKind::NODE_WITH => {
+            if let (Some(scope), Some(body)) = (node.first_child(), node.last_child()) {
+                withvals.push(scope.to_string());
+                listsegments_aux(&body, condition, withvals, out);
+            }
+        }
+        SyntaxKind::NODE_PAREN => {
+            if let Some(x) = node.last_child() {
+                listsegments_aux(&x, condition, withvals, out);
+            }
         }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let mut out = vec![];
-            for elem in child.children() {
-                out.push(elem.to_string());
+        SyntaxKind::NODE_BIN_OP
+            if node.children_with_tokens().any(|x| x.kind() == SyntaxKind::TOKEN_CONCAT) =>
+        {
+            for child in node.children() {
+                listsegments_aux(&child, condition.clone(), withvals.clone(), out);
             }
-            return Some(out);
         }
+        // `lib.optionals cond [ ... ]`, which is applied to the condition first
+        SyntaxKind::NODE_APPLY => {
+            let (inner, list) = match (node.first_child(), node.last_child()) {
+                (Some(x), Some(y)) if x.kind() == SyntaxKind::NODE_APPLY => (x, y),
+                _ => return,
+            };
+            let (function, cond) = match (inner.first_child(), inner.last_child()) {
+                (Some(x), Some(y)) => (x.to_string(), y.to_string()),
+                _ => return,
+            };
+            if !["optionals", "lib.optionals", "lib.lists.optionals"].contains(&function.as_str()) {
+                return;
+            }
+            listsegments_aux(&list, Some(both(condition, &cond)), withvals, out);
+        }
+        _ => {}
     }
-    None
 }
 
-pub fn getwithvalue(f: &str, query: &str) -> Result<Vec<String>, ReadError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+// The condition for a list under both `outer` and `inner`
+fn both(outer: Option<String>, inner: &str) -> String {
+    match outer {
+        Some(c) => format!("{} && {}", argument(&c), argument(inner)),
+        None => inner.to_string(),
+    }
+}
+
+// The items that are not in the list `query` yet, without repeating any. All items
+// are missing if there is no such attribute.
+pub fn missingarr(f: &str, query: &str, items: &[String]) -> Result<Vec<String>, Error> {
+    let root = parsechecked(f)?;
+    missingarr_node(&root, query, items)
+}
+
+pub(crate) fn missingarr_node(
+    root: &SyntaxNode,
+    query: &str,
+    items: &[String],
+) -> Result<Vec<String>, Error> {
+    let configbase = match getcfgbase(root) {
         Some(x) => x,
-        None => {
-            return Err(ReadError::ParseError);
+        None => return Err(ParseError::default().into()),
+    };
+    // Elements that are only added under a condition may be missing
+    let mut segments = match findattr(&configbase, query) {
+        Some(x) => {
+            let segments = listsegments(&x);
+            if segments.is_empty() {
+                return Err(attrerror(ErrorKind::Array, query, &root.to_string()));
+            }
+            segments
+                .into_iter()
+                .filter(|(_, condition, _)| condition.is_none())
+                .map(|(list, _, withvals)| {
+                    let keys = list.children().map(|e| elemkey(&e.to_string(), &withvals));
+                    (keys.collect::<Vec<_>>(), withvals)
+                })
+                .collect::<Vec<_>>()
+        }
+        None => vec![],
+    };
+    // Each list compares the items in its own scopes. The items that are added go
+    // to the first one, like `insertarr` puts them.
+    if segments.is_empty() {
+        segments.push((vec![], vec![]));
+    }
+    let mut out = vec![];
+    for item in items {
+        if segments.iter().any(|(keys, withvals)| keys.contains(&elemkey(item, withvals))) {
+            continue;
         }
+        let (keys, withvals) = &mut segments[0];
+        keys.push(elemkey(item, withvals));
+        out.push(item.clone());
+    }
+    Ok(out)
+}
+
+// A list element in a form that is the same for elements that only differ in
+// whitespace, comments and parenthesis around them, or in naming a scope the list
+// is already `with`, as `pkgs.firefox` and `firefox` do in `with pkgs; [ ... ]`
+pub fn elemkey(elem: &str, withvals: &[String]) -> String {
+    let tokens = |x: &str| {
+        let root = rnix::parse(x).node();
+        let mut node = root.first_child().unwrap_or(root);
+        while node.kind() == SyntaxKind::NODE_PAREN {
+            match node.first_child() {
+                Some(x) => node = x,
+                None => break,
+            }
+        }
+        node.descendants_with_tokens()
+            .filter_map(|x| x.into_token())
+            .filter(|x| !matches!(x.kind(), SyntaxKind::TOKEN_WHITESPACE | SyntaxKind::TOKEN_COMMENT))
+            .map(|x| x.text().to_string())
+            .collect::<Vec<_>>()
     };
+    let key = tokens(elem);
+    for scope in withvals {
+        let mut prefix = tokens(scope);
+        prefix.push(".".to_string());
+        if key.len() > prefix.len() && key.starts_with(&prefix) {
+            return key[prefix.len()..].join(" ");
+        }
+    }
+    key.join(" ")
+}
+
+pub fn getwithvalue(f: &str, query: &str) -> Result<Vec<String>, Error> {
+    let configbase = parseconfig(f)?;
     let output = match findattr(&configbase, query) {
         Some(x) => match getwithval_aux(&x, vec![]) {
             Some(y) => y,
-            None => return Err(ReadError::NoAttr),
+            None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
         },
-        None => return Err(ReadError::NoAttr),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, f)),
     };
     Ok(output)
 }
 
-fn getwithval_aux(
+pub(crate) fn getwithval_aux(
     node: &SyntaxNode,
     mut withvals: Vec<String>,
 ) -> Option<Vec<String>> {
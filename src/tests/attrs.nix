@@ -0,0 +1,19 @@
+{ config, lib, pkgs, ... }:
+
+let
+  name = "example";
+in
+{
+  inherit name;
+  inherit (pkgs) hello;
+
+  networking.hosts."127.0.0.1" = [ "localhost" ];
+  services.nginx = rec {
+    enable = true;
+    virtualHosts.${name}.root = "/var/www";
+    user = config.services.nginx.group;
+  };
+  programs = lib.mkIf true {
+    git.enable = lib.mkDefault true;
+  };
+}
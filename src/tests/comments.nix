@@ -0,0 +1,16 @@
+{ config, pkgs, ... }:
+
+{
+  imports = [ ./hardware-configuration.nix ];
+
+  # Keep the old kernel until the wifi driver is fixed
+  # https://example.com/issue/123
+  boot.kernelPackages = pkgs.linuxPackages_5_15;
+  networking.hostName = "nixos"; # Define your hostname.
+  time.timeZone = "Europe/Amsterdam";
+
+  services.openssh = {
+    /* Only reachable from the VPN */
+    enable = true;
+  };
+}
@@ -0,0 +1,24 @@
+{ config, lib, pkgs, ... }:
+
+let
+  cfg = config.desktop;
+in
+{
+  environment.systemPackages = with pkgs; [
+    git
+    htop
+  ] ++ lib.optionals cfg.gui [
+    firefox
+    htop
+  ];
+
+  fonts.packages = (import ./fonts.nix pkgs) ++ [ pkgs.noto-fonts ];
+
+  boot.kernelModules = lib.optionals cfg.virtualisation [ "kvm-intel" ];
+
+  networking.firewall.allowedTCPPorts = [ 22 ] ++ optionals cfg.web ([ 80 443 ] ++ lib.optionals cfg.tls [ 8443 ]);
+
+  environment.shells = [ pkgs.bashInteractive ] ++ (with pkgs; [ zsh ]);
+
+  services.xserver.excludePackages = lib.mkIf cfg.gui ([ pkgs.xterm ] ++ lib.optionals (!cfg.tls) [ pkgs.xclock ]);
+}
@@ -0,0 +1,7 @@
+{ extra }:
+
+{
+  imports = [ extra ];
+
+  networking.hostName = "nixos";
+}
@@ -0,0 +1,5 @@
+{ ... }:
+
+{
+  time.timeZone = "Europe/Amsterdam";
+}
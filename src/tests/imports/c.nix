@@ -0,0 +1,7 @@
+extra:
+
+{
+  imports = [ extra ];
+
+  boot.loader.grub.enable = false;
+}
@@ -0,0 +1,10 @@
+{ config, pkgs, ... }:
+
+{
+  imports = [
+    (import ./a.nix { extra = ./b.nix; })
+    (import ./c.nix ./b.nix)
+  ] ++ [ ./d.nix ];
+
+  system.stateVersion = "22.05";
+}
@@ -0,0 +1,10 @@
+{ pkgs, ... }:
+{
+    networking.hostName = "a";
+    services.foo = {
+        enable = true;
+    };
+    environment.systemPackages = with pkgs; [
+        git
+    ];
+}
@@ -0,0 +1,10 @@
+{ config, pkgs, ... }:
+
+{
+  users = {
+    inherit (config) mutableUsers;
+    defaultUserShell = pkgs.zsh;
+  };
+
+  a = { inherit b; c = 1; };
+}
@@ -0,0 +1,12 @@
+{ config, pkgs, lib, ... }:
+
+let
+  cfg = config.services.foo;
+  users = {
+    alice = { isNormalUser = true; };
+  };
+in
+rec {
+  networking.hostName = "nixos";
+  services.openssh.enable = true;
+}
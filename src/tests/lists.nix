@@ -0,0 +1,12 @@
+{ pkgs, ... }:
+{
+  environment.systemPackages = with pkgs; [
+    firefox # browser
+    # editors
+    neovim
+    wget
+  ];
+  services.openssh.ports = [ 22 ];
+  networking.firewall.allowedTCPPorts = [];
+  boot.kernelModules = [ ];
+}
@@ -0,0 +1,17 @@
+{ config, lib, pkgs, ... }:
+let
+  cfg = config.services.foo;
+in
+{
+  options.services.foo.enable = lib.mkEnableOption "foo";
+
+  config = lib.mkIf cfg.enable (lib.mkMerge [
+    {
+      services.foo.port = lib.mkDefault 8080;
+      environment.systemPackages = lib.mkBefore [ pkgs.foo ];
+    }
+    (lib.mkIf cfg.openFirewall {
+      networking.firewall.allowedTCPPorts = lib.mkOverride 50 [ cfg.port ];
+    })
+  ]);
+}
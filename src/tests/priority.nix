@@ -0,0 +1,11 @@
+{ config, lib, pkgs, ... }:
+{
+  services.foo.enable = lib.mkForce true;
+  services.foo.port = lib.mkOverride 50 (8000 + 80);
+  services.foo.user = "foo";
+  config = lib.mkIf config.x.enable {
+    a = lib.mkDefault [
+      1
+    ];
+  };
+}
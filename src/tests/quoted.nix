@@ -0,0 +1,9 @@
+{
+  fileSystems."/boot" = {
+    device = "/dev/disk/by-label/boot";
+    fsType = "vfat";
+  };
+  networking.hosts."127.0.0.1" = [ "localhost" ];
+  services."nginx".enable = true;
+  "foo.bar" = 1;
+}
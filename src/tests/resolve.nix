@@ -0,0 +1,11 @@
+{ config, lib, ... }:
+
+{
+  imports = [ ./hardware-configuration.nix ];
+
+  hardware = import ./hardware.nix;
+  services.nginx.enable = true;
+  services.openssh = lib.mkIf config.services.nginx.enable {
+    enable = true;
+  };
+}
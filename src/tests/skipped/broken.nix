@@ -0,0 +1,7 @@
+{ config, ... }:
+
+{
+  imports = [ ./fonts.nix ];
+
+  services.printing.enable = true
+}
@@ -0,0 +1,10 @@
+{ config, pkgs, ... }:
+
+{
+  imports = [
+    ./broken.nix
+    ./users.nix
+  ];
+
+  system.stateVersion = "22.05";
+}
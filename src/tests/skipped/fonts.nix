@@ -0,0 +1,5 @@
+{ pkgs, ... }:
+
+{
+  fonts.packages = [ pkgs.noto-fonts ];
+}
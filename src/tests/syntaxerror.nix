@@ -0,0 +1,6 @@
+{ config, pkgs, ... }:
+
+{
+  networking.hostName = "nixos"
+  time.timeZone = "Europe/Amsterdam";
+}
@@ -1,9 +1,17 @@
 use crate::{
-    read::{getarrvals, readvalue, ReadError, getwithvalue},
-    write::{ addtoarr, deref, write, rmarr }, parse::{collectattrs, getcfgbase, get_collection},
+    comment::Comments,
+    document::{Document, Operation},
+    errors::{Error, ErrorKind},
+    options::{Options, ValidationError},
+    packages::Packages,
+    read::{elemkey, getarrsegments, getarrvals, missingarr, ListSegment, readvalue, read_typed, readcomments, readlocation, readresolution, readwrapped, getwithvalue},
+    value::NixValue,
+    workspace::Workspace,
+    wrapper::Wrapper,
+    write::{ addtoarr, addwrapper, deref, ensurearr, insertarr, removearr, replacearr, write, write_inner, write_typed, rmarr, rmwrapper, setcomment, Position }, parse::{attrs, collectattrs, getcfgbase, get_collection, AttrPath, Resolution},
 };
 use core::panic;
-use std::{fs, path::Path, collections::HashMap};
+use std::{fs, path::{Path, PathBuf}, collections::HashMap};
 
 #[test]
 fn read_val1() {
@@ -47,8 +55,8 @@ fn read_val3() {
     };
 
     // Check if read error is correct
-    match out {
-        ReadError::NoAttr => (),
+    match out.kind {
+        ErrorKind::NoAttr => (),
         _ => panic!("Incorrect error type"),
     }
 }
@@ -98,6 +106,25 @@ fn read_val6() {
     assert!(out == "{\n  b = true;\n  c = {\n    d = \"test\";\n  };\n}")
 }
 
+#[test]
+fn read_val7() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/inherit.nix")).expect("Failed to read file");
+
+    // Sets with `inherit` keep it when read
+    let out = match readvalue(&config, "a") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "{\n  inherit b;\n  c = 1;\n}", "{}", out);
+
+    let out = match readvalue(&config, "users") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "{\n  inherit (config) mutableUsers;\n  defaultUserShell = pkgs.zsh;\n}", "{}", out);
+}
+
 #[test]
 fn readarr_val1() {
     let config =
@@ -255,6 +282,31 @@ fn write_val6() {
     assert!(out == "{\n  a = {\n    b = true;\n  };\n  a.c = {\n    d = \"test\";\n  };\n  x = { y = false; z = \"test\"; };\n}")
 }
 
+#[test]
+fn write_val7() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    // Segments that are not identifiers are quoted in new bindings
+    let out = write(&config, "a b", "1").unwrap();
+    assert!(out.contains("\n  \"a b\" = 1;\n"));
+    assert!(readvalue(&out, "\"a b\"").unwrap() == "1");
+    let out = insertarr(&config, "x.\"y z\"", vec!["1".to_string()], &Position::End).unwrap();
+    assert!(out.contains("\n  x.\"y z\" = [\n    1\n  ];\n"));
+
+    // Paths that can not be written fail instead of breaking the file
+    for query in ["a..b", "a.", "", "\"a", "a.\"b"] {
+        match write(&config, query, "1") {
+            Err(Error { kind: ErrorKind::InvalidAttr(_), .. }) => {}
+            _ => panic!("Wrote to {:?}", query),
+        }
+        match addtoarr(&config, query, vec!["1".to_string()]) {
+            Err(Error { kind: ErrorKind::InvalidAttr(_), .. }) => {}
+            _ => panic!("Added to {:?}", query),
+        }
+    }
+}
+
 #[test]
 fn write_format1() {
     let config =
@@ -407,12 +459,30 @@ fn deref_val1() {
     };
 
     // Check if read value is "test"
-    match r {
-        ReadError::NoAttr => (),
+    match r.kind {
+        ErrorKind::NoAttr => (),
         _ => panic!("Incorrect error for no attribute"),
     }
 }
 
+#[test]
+fn deref_val2() {
+    // Bindings whose value is not a set do not stop the search
+    let out = match deref("{\n  a = import ./a.nix;\n  a.b = 1;\n  c = 2;\n}", "a.b") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out == "{\n  a = import ./a.nix;\n  c = 2;\n}");
+
+    // Sets under `mkIf` and `mkMerge` are searched
+    let config = "{ lib, ... }:\n{\n  a = lib.mkMerge [\n    { b = 1; }\n    (lib.mkIf true {\n      c = 2;\n      d = 3;\n    })\n  ];\n}";
+    let out = match deref(config, "a.c") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out == "{ lib, ... }:\n{\n  a = lib.mkMerge [\n    { b = 1; }\n    (lib.mkIf true {\n      d = 3;\n    })\n  ];\n}");
+}
+
 #[test]
 fn get_with1() {
     let config =
@@ -518,4 +588,1458 @@ fn collect2() {
     assert!(out.get("boot.loader.efi.canTouchEfiVariables") == Some(&String::from("true")));
     assert!(out.get("programs.gnupg.agent.enableSSHSupport") == Some(&String::from("true")));
     assert!(out.get("system.stateVersion") == Some(&String::from("\"22.05\"")));
-}
\ No newline at end of file
+}
+#[test]
+fn attrs1() {
+    let config = fs::read_to_string(Path::new("src/tests/attrs.nix")).unwrap();
+    let configbase = getcfgbase(&rnix::parse(&config).node()).unwrap();
+    let paths = attrs(&configbase, None).map(|(p, _, _)| p.to_string()).collect::<Vec<_>>();
+    assert!(paths == vec![
+        "name",
+        "hello",
+        "networking.hosts.\"127.0.0.1\"",
+        "services.nginx",
+        "services.nginx.enable",
+        "services.nginx.virtualHosts.${name}.root",
+        "services.nginx.user",
+        "programs",
+        "programs.git.enable",
+    ]);
+
+    let (_, value, loc) = attrs(&configbase, None).nth(1).unwrap();
+    assert!(value.to_string() == "hello");
+    assert!((loc.line, loc.column) == (8, 18));
+    let (_, value, loc) = attrs(&configbase, None).last().unwrap();
+    assert!(value.to_string() == "lib.mkDefault true");
+    assert!(&config[loc.start..loc.end] == "git.enable = lib.mkDefault true;");
+
+    // Nested sets are listed but not entered past the depth limit
+    let paths = attrs(&configbase, Some(0)).map(|(p, _, _)| p.to_string()).collect::<Vec<_>>();
+    assert!(paths == vec!["name", "hello", "networking.hosts.\"127.0.0.1\"", "services.nginx", "programs"]);
+
+    let map = get_collection(config).unwrap();
+    assert!(map.get("hello") == Some(&String::from("pkgs.hello")));
+    assert!(map.get("programs.git.enable") == Some(&String::from("true")));
+    assert!(!map.contains_key("services.nginx"));
+}
+
+#[test]
+fn parse_error1() {
+    let config = fs::read_to_string(Path::new("src/tests/syntaxerror.nix")).unwrap();
+    let e = match readvalue(&config, "time.timeZone") {
+        Err(Error { kind: ErrorKind::Parse(e), .. }) => e,
+        _ => panic!("Read from a file with syntax errors"),
+    };
+    assert!(!e.errors.is_empty());
+    assert!((e.errors[0].location.line, e.errors[0].location.column) == (5, 17));
+    assert!(matches!(write(&config, "time.timeZone", "\"UTC\""), Err(Error { kind: ErrorKind::Parse(_), .. })));
+    assert!(Document::parse(&config).is_err());
+
+    // A valid file that is not a configuration has no syntax errors to report
+    match readvalue("[ 1 2 ]", "a") {
+        Err(Error { kind: ErrorKind::Parse(e), .. }) => assert!(e.errors.is_empty()),
+        _ => panic!("Read from a list"),
+    };
+}
+
+#[test]
+fn parse_error2() {
+    let config = fs::read_to_string(Path::new("src/tests/configuration.nix")).unwrap();
+    for val in ["", "lib.mkIf (", "{ a = 1; "] {
+        assert!(matches!(write(&config, "networking.hostName", val), Err(Error { kind: ErrorKind::InvalidValue(_), .. })));
+        assert!(matches!(write_inner(&config, "networking.hostName", val), Err(Error { kind: ErrorKind::InvalidValue(_), .. })));
+    }
+    assert!(matches!(
+        addtoarr(&config, "environment.systemPackages", vec!["git".to_string(), "(".to_string()]),
+        Err(Error { kind: ErrorKind::InvalidValue(_), .. })
+    ));
+}
+
+#[test]
+fn error_render1() {
+    let config = fs::read_to_string(Path::new("src/tests/comments.nix")).unwrap();
+    let e = match write(&config, "services.openssh.ports", "[ 22 ]").and_then(|x| addtoarr(&x, "services.openssh.enable", vec!["22".to_string()])) {
+        Ok(_) => panic!("Added to a value that is not a list"),
+        Err(e) => e.withfile(Path::new("configuration.nix")),
+    };
+    assert!(e.kind == ErrorKind::Array);
+    assert!(e.code() == "array_error");
+    assert!(e.span.as_ref().unwrap().file == Some(PathBuf::from("configuration.nix")));
+    assert!(e.render(&config) == "error: `services.openssh.enable` is not a list
+  --> configuration.nix:14:14
+   |
+14 |     enable = true;
+   |              ^^^^ this is not a list");
+
+    // Missing attributes point at the deepest part of their path that exists
+    let e = readvalue(&config, "services.openssh.ports").unwrap_err();
+    assert!(e.label() == Some("`services.openssh` has no attribute `ports`".to_string()));
+    assert!(e.render(&config) == "error: no attribute `services.openssh.ports`
+  --> <input>:12:3
+   |
+12 |   services.openssh = {
+   |   ^^^^^^^^^^^^^^^^ `services.openssh` has no attribute `ports`");
+    let e = readvalue(&config, "a.b").unwrap_err();
+    assert!(e.span.is_none());
+    assert!(e.render(&config) == "error: no attribute `a.b`");
+}
+
+#[test]
+fn error_render2() {
+    let config = fs::read_to_string(Path::new("src/tests/syntaxerror.nix")).unwrap();
+    let e = readvalue(&config, "time.timeZone").unwrap_err();
+    assert!(e.code() == "parse_error");
+    assert!(e.span.as_ref().map(|x| (x.line, x.column)) == Some((5, 17)));
+    assert!(e.render(&config).starts_with("error: syntax error\n --> <input>:5:17\n  |\n5 |   time.timeZone = \"Europe/Amsterdam\";\n  |                 ^"));
+
+    // Errors from a workspace know the module they happened in
+    let mut ws = Workspace::open(Path::new("src/tests/workspace/configuration.nix")).unwrap();
+    let ops = vec![Operation::ListAdd { attr: "services.nginx.enable".to_string(), items: vec!["1".to_string()] }];
+    let e = ws.apply(&ops).unwrap_err();
+    assert!(e.file == Some(PathBuf::from("src/tests/workspace/modules/web/default.nix")));
+    assert!(e.attr == Some("config.services.nginx.enable".to_string()));
+    assert!(e.span.map(|x| x.line) == Some(7));
+}
+
+#[test]
+fn read_resolve1() {
+    let config = fs::read_to_string(Path::new("src/tests/resolve.nix")).unwrap();
+    let missing = |prefix: &str, missing: &str| Resolution::Missing { prefix: AttrPath::parse(prefix), missing: missing.to_string() };
+    assert!(readresolution(&config, "services.openssh.enable").unwrap() == Resolution::Found);
+    assert!(readresolution(&config, "services.openssh.ports").unwrap() == missing("services.openssh", "ports"));
+    assert!(readresolution(&config, "services.nginx.user").unwrap() == missing("services.nginx", "user"));
+    assert!(readresolution(&config, "services.xserver.enable").unwrap() == missing("services", "xserver"));
+    assert!(readresolution(&config, "boot.loader").unwrap() == Resolution::Missing { prefix: AttrPath::default(), missing: "boot".to_string() });
+
+    // Values that are not sets can not be looked into
+    let res = readresolution(&config, "hardware.cpu.intel").unwrap();
+    assert!(res == Resolution::NotAttrSet { prefix: AttrPath::parse("hardware"), missing: "cpu".to_string() });
+    let res = readresolution(&config, "services.nginx.enable.foo").unwrap();
+    assert!(res == Resolution::NotAttrSet { prefix: AttrPath::parse("services.nginx.enable"), missing: "foo".to_string() });
+}
+
+#[test]
+fn error_resolve1() {
+    let config = fs::read_to_string(Path::new("src/tests/resolve.nix")).unwrap();
+    let e = match write(&config, "hardware.cpu.intel.updateMicrocode", "true") {
+        Err(e) => e,
+        _ => panic!("Wrote into a value that is not a set"),
+    };
+    assert!(e.kind == ErrorKind::NotAttrSet);
+    assert!(e.attr == Some("hardware.cpu.intel.updateMicrocode".to_string()));
+    assert!(e.prefix == Some(AttrPath::parse("hardware")));
+    assert!(e.missing() == Some("cpu".to_string()));
+    assert!(&config[e.span.as_ref().unwrap().start..e.span.as_ref().unwrap().end] == "import ./hardware.nix");
+    assert!(matches!(addtoarr(&config, "hardware.firmware", vec!["pkgs.foo".to_string()]), Err(Error { kind: ErrorKind::NotAttrSet, .. })));
+
+    let e = Error::new(ErrorKind::NoAttr).withattr("services.openssh.ports").locate(&config);
+    assert!(e.kind == ErrorKind::NoAttr);
+    assert!(e.missing() == Some("ports".to_string()));
+    assert!(e.to_string() == "no attribute `services.openssh.ports`");
+    assert!(e.label() == Some("`services.openssh` has no attribute `ports`".to_string()));
+}
+
+#[test]
+fn options_check1() {
+    let options = match Options::load(Path::new("src/tests/options.json")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to load options"),
+    };
+    assert!(options.len() == 21);
+    assert!(options.find("users.users.alice.isNormalUser").map(|x| x.typename.as_str()) == Some("boolean"));
+    assert!(options.find("environment.systemPackages").unwrap().description.is_some());
+
+    assert!(options.check("services.openssh.enable", "true").is_ok());
+    assert!(options.check("services.openssh.enable", "lib.mkForce true").is_ok());
+    assert!(options.check("services.openssh.ports", "[ 22 2222 ]").is_ok());
+    assert!(options.check("environment.systemPackages", "with pkgs; [ git ]").is_ok());
+    assert!(options.check("time.timeZone", "null").is_ok());
+    assert!(options.check("boot.loader.grub.version", "2").is_ok());
+    assert!(options.check("boot.kernel.sysctl.\"net.ipv4.ip_forward\"", "1").is_ok());
+    assert!(options.check("services.openssh.settings.X11Forwarding", "true").is_ok());
+    assert!(options.check("users.users.alice", "{ isNormalUser = true; extraGroups = [ \"wheel\" ]; }").is_ok());
+    assert!(options.check("networking.interfaces.eth0.ipv4.addresses", "[ { address = \"10.0.0.2\"; prefixLength = 24; } ]").is_ok());
+
+    // Misspelled options suggest the closest existing names
+    match options.check("services.openssh.enabled", "true") {
+        Err(ValidationError::UnknownOption { attr, suggestions }) => {
+            assert!(attr == "services.openssh.enabled");
+            assert!(suggestions == vec!["services.openssh.enable"]);
+        }
+        _ => panic!("Accepted an unknown option"),
+    }
+    match options.check("servics.nginx", "{ enable = true; }") {
+        Err(ValidationError::UnknownOption { suggestions, .. }) => assert!(suggestions == vec!["services.nginx"]),
+        _ => panic!("Accepted an unknown option"),
+    }
+    match options.check("users.users.alice", "{ isNormalUsr = true; }") {
+        Err(ValidationError::UnknownOption { attr, suggestions }) => {
+            assert!(attr == "users.users.alice.isNormalUsr");
+            assert!(suggestions == vec!["users.users.alice.isNormalUser"]);
+        }
+        _ => panic!("Accepted an unknown option"),
+    }
+}
+
+#[test]
+fn options_check2() {
+    let options = Options::load(Path::new("src/tests/options.json")).unwrap();
+    let wrongtype = |attr: &str, val: &str| matches!(options.check(attr, val), Err(ValidationError::WrongType { .. }));
+    assert!(wrongtype("services.openssh.enable", "\"yes\""));
+    assert!(wrongtype("services.openssh.ports", "[ 22 70000 ]"));
+    assert!(wrongtype("networking.firewall.allowedTCPPorts", "80"));
+    assert!(wrongtype("boot.loader.grub.version", "3"));
+    assert!(wrongtype("environment.systemPackages", "[ \"git\" ]"));
+    assert!(wrongtype("networking.interfaces.eth0.ipv4.addresses", "[ { address = \"10.0.0.2\"; prefixLength = 40; } ]"));
+    assert!(wrongtype("services.nginx", "true"));
+    assert!(matches!(options.check("system.nixos.release", "\"23.11\""), Err(ValidationError::ReadOnly { .. })));
+
+    let e = Error::from(options.check("services.openssh.enable", "1").unwrap_err());
+    assert!(e.code() == "wrong_type");
+    assert!(e.to_string() == "`services.openssh.enable` must be boolean, not `1`");
+}
+
+#[test]
+fn packages_check1() {
+    let packages = match Packages::load(Path::new("src/tests/packages.json")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to load packages"),
+    };
+    // The `nixpkgs.` channel prefix is dropped
+    assert!(packages.len() == 11);
+    assert!(packages.contains("python3Packages.requests"));
+    assert!(!packages.contains("nixpkgs.git"));
+
+    let with = vec!["pkgs".to_string()];
+    assert!(packages.check("environment.systemPackages", "git", &with).is_ok());
+    assert!(packages.check("environment.systemPackages", "pkgs.git", &[]).is_ok());
+    assert!(packages.check("environment.systemPackages", "python3Packages.numpy", &with).is_ok());
+    // Expressions and names that are not from `pkgs` can not be checked
+    assert!(packages.check("environment.systemPackages", "(python3.withPackages (ps: [ ps.numpy ]))", &with).is_ok());
+    assert!(packages.check("environment.systemPackages", "gti", &[]).is_ok());
+    assert!(packages.check("environment.systemPackages", "config.boot.kernelPackages.perf", &[]).is_ok());
+
+    match packages.check("environment.systemPackages", "gti", &with) {
+        Err(ValidationError::UnknownPackage { attr, package, suggestions }) => {
+            assert!(attr == "environment.systemPackages");
+            assert!(package == "gti");
+            assert!(suggestions == vec!["git"]);
+        }
+        _ => panic!("Accepted an unknown package"),
+    }
+    match packages.check("environment.systemPackages", "pkgs.neovimm", &[]) {
+        Err(ValidationError::UnknownPackage { suggestions, .. }) => assert!(suggestions == vec!["pkgs.neovim"]),
+        _ => panic!("Accepted an unknown package"),
+    }
+    match packages.check("environment.systemPackages", "pkgs.python3Packages.request", &[]) {
+        Err(ValidationError::UnknownPackage { suggestions, .. }) => {
+            assert!(suggestions == vec!["pkgs.python3Packages.requests"])
+        }
+        _ => panic!("Accepted an unknown package"),
+    }
+}
+
+#[test]
+fn packages_load() {
+    // Only the channel and `legacyPackages.<system>` are dropped, even from a single name
+    let packages = Packages::from_json("{ \"nixpkgs.git\": {} }").unwrap();
+    assert!(packages.contains("git"));
+    let packages = Packages::from_json("{ \"legacyPackages.x86_64-linux.python3Packages.numpy\": {} }").unwrap();
+    assert!(packages.contains("python3Packages.numpy"));
+    let packages = Packages::from_json("{ \"python3Packages.numpy\": {}, \"python3Packages.requests\": {} }").unwrap();
+    assert!(packages.contains("python3Packages.numpy"));
+    let packages = Packages::from_json("{ \"\": {}, \"nixos.htop\": {} }").unwrap();
+    assert!(packages.len() == 2);
+    assert!(packages.contains("htop"));
+}
+
+#[test]
+fn packages_check2() {
+    let config = fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+    let packages = Packages::load(Path::new("src/tests/packages.json")).unwrap();
+
+    // The list is under `with pkgs;`
+    let items = vec!["htop".to_string(), "pkgs.ripgrep".to_string()];
+    assert!(packages.checkitems(&config, "environment.systemPackages", &items).is_ok());
+    let items = vec!["htop".to_string(), "fierfox".to_string()];
+    let e = match packages.checkitems(&config, "environment.systemPackages", &items) {
+        Ok(_) => panic!("Accepted an unknown package"),
+        Err(e) => Error::from(e).locate(&config),
+    };
+    assert!(e.code() == "unknown_package");
+    assert!(e.to_string() == "no package `fierfox`, did you mean `firefox`?");
+    assert!(e.span.map(|x| x.line) == Some(71));
+}
+
+#[test]
+fn insert_arr1() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+    let items = vec!["htop".to_string()];
+
+    let out = match insertarr(&config, "environment.systemPackages", items.clone(), &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    // Inserted above the comment of the element after it
+    assert!(out.contains("    firefox # browser\n    htop\n    # editors\n    neovim\n"));
+
+    let out = match insertarr(&config, "environment.systemPackages", items.clone(), &Position::Before("neovim".to_string())) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["firefox", "htop", "neovim", "wget"]);
+
+    let out = match insertarr(&config, "environment.systemPackages", items.clone(), &Position::After("neovim".to_string())) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["firefox", "neovim", "htop", "wget"]);
+
+    let out = match insertarr(&config, "environment.systemPackages", vec!["zsh".to_string(), "atop".to_string()], &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["atop", "firefox", "neovim", "wget", "zsh"]);
+
+    // An unsorted list is added to at the end
+    let out = match insertarr(&out, "environment.systemPackages", vec!["bash".to_string()], &Position::Index(5)) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    let out = match insertarr(&out, "environment.systemPackages", vec!["curl".to_string()], &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["atop", "firefox", "neovim", "wget", "zsh", "bash", "curl"]);
+}
+
+#[test]
+fn insert_arr2() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+
+    // Lists on one line stay on one line
+    let out = match insertarr(&config, "services.openssh.ports", vec!["2222".to_string()], &Position::End) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("services.openssh.ports = [ 22 2222 ];"));
+    let out = match insertarr(&out, "services.openssh.ports", vec!["21".to_string()], &Position::Index(0)) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("services.openssh.ports = [ 21 22 2222 ];"));
+    let out = match addtoarr(&out, "networking.firewall.allowedTCPPorts", vec!["80".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("networking.firewall.allowedTCPPorts = [ 80 ];"));
+    let out = match addtoarr(&out, "boot.kernelModules", vec!["kvm".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("boot.kernelModules = [ kvm ];"));
+
+    match insertarr(&config, "services.openssh.ports", vec!["2".to_string()], &Position::Before("23".to_string())) {
+        Err(Error { kind: ErrorKind::NoElement(x), .. }) => assert!(x == vec!["23"]),
+        _ => panic!("Inserted before a missing element"),
+    }
+    match insertarr(&config, "services.openssh.ports", vec!["2222".to_string()], &Position::Index(2)) {
+        Err(Error { kind: ErrorKind::IndexOutOfRange(i), .. }) => assert!(i == 2),
+        _ => panic!("Inserted past the end of the list"),
+    }
+    let e = insertarr(&config, "services.openssh.ports", vec!["2222".to_string()], &Position::Index(5)).unwrap_err();
+    assert!(e.code() == "index_out_of_range");
+    assert!(e.to_string() == "cannot insert at index 5, it is past the end of `services.openssh.ports`");
+    assert!(e.span.map(|x| x.line) == Some(9));
+    let e = insertarr(&config, "services.openssh.ports", vec!["2".to_string()], &Position::After("23".to_string())).unwrap_err();
+    assert!(e.code() == "no_element");
+    assert!(e.to_string() == "`services.openssh.ports` has no element `23`");
+    assert!(e.span.map(|x| x.line) == Some(9));
+}
+
+#[test]
+fn insert_arr3() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+
+    // Function applications are added in parenthesis, so they stay one element
+    let items = vec!["callPackage ./p.nix { }".to_string(), "f y".to_string(), "htop".to_string()];
+    let out = match insertarr(&config, "services.openssh.ports", items.clone(), &Position::Index(0)) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("services.openssh.ports = [ (callPackage ./p.nix { }) (f y) htop 22 ];"));
+    let out = match addtoarr(&config, "environment.systemPackages", items) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("    wget\n    (callPackage ./p.nix { })\n    (f y)\n    htop\n  ];"));
+}
+
+#[test]
+fn insert_arr4() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    // Anchors are found under `with pkgs;` and in parenthesis
+    let out = match insertarr(&config, "environment.systemPackages", vec!["htop".to_string()], &Position::Before("pkgs.wget".to_string())) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["vim", "htop", "wget", "firefox"]);
+    let out = match insertarr(&config, "environment.systemPackages", vec!["htop".to_string()], &Position::After("(vim)".to_string())) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["vim", "htop", "wget", "firefox"]);
+
+    match insertarr(&config, "environment.systemPackages", vec!["htop".to_string()], &Position::Before("lib.wget".to_string())) {
+        Err(Error { kind: ErrorKind::NoElement(x), .. }) => assert!(x == vec!["lib.wget"]),
+        _ => panic!("Inserted before a missing element"),
+    }
+}
+
+#[test]
+fn ensure_arr1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    // `pkgs.firefox` is `firefox` under `with pkgs;`, and `htop` is only added once
+    let items = vec!["pkgs.firefox".to_string(), "htop".to_string(), " htop ".to_string(), "wget".to_string()];
+    let (out, added) = match ensurearr(&config, "environment.systemPackages", items.clone(), &Position::End) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added == vec!["htop"]);
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["vim", "wget", "firefox", "htop"]);
+
+    // Running it again changes nothing
+    let (out2, added) = match ensurearr(&out, "environment.systemPackages", items, &Position::End) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added.is_empty());
+    assert!(out2 == out);
+
+    // New lists get every item once
+    let (out, added) = match ensurearr(&config, "test.arr", vec!["a".to_string(), "a".to_string()], &Position::End) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added == vec!["a"]);
+    assert!(getarrvals(&out, "test.arr").unwrap() == vec!["a"]);
+
+    assert!(matches!(
+        ensurearr(&config, "system.stateVersion", vec!["a".to_string()], &Position::End),
+        Err(Error { kind: ErrorKind::Array, .. })
+    ));
+}
+
+#[test]
+fn ensure_arr2() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+    let with = vec!["pkgs".to_string()];
+    assert!(elemkey("pkgs.firefox", &with) == elemkey("firefox", &with));
+    assert!(elemkey("pkgs.firefox", &[]) != elemkey("firefox", &[]));
+    assert!(elemkey("(python3.withPackages (ps: [ ps.numpy ]))", &[]) == elemkey("( python3.withPackages(ps: [ps.numpy]) )", &[]));
+    assert!(elemkey("\"a  b\"", &[]) != elemkey("\"a b\"", &[]));
+
+    let items = vec!["neovim".to_string(), "htop".to_string()];
+    assert!(missingarr(&config, "environment.systemPackages", &items).unwrap() == vec!["htop"]);
+    assert!(missingarr(&config, "services.openssh.ports", &["22".to_string(), "2222".to_string()]).unwrap() == vec!["2222"]);
+
+    let mut doc = Document::parse(&config).unwrap();
+    let added = match doc.ensurearr("environment.systemPackages", items, &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added == vec!["htop"]);
+    assert!(getarrvals(&doc.to_string(), "environment.systemPackages").unwrap() == vec!["firefox", "htop", "neovim", "wget"]);
+}
+
+#[test]
+fn ensure_arr3() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+
+    // Elements added in parenthesis are found again without them
+    assert!(elemkey("(f y)", &[]) == elemkey("f y", &[]));
+    assert!(elemkey("((pkgs.git))", &["pkgs".to_string()]) == elemkey("git", &["pkgs".to_string()]));
+    let items = vec!["callPackage ./p.nix { }".to_string(), "f y".to_string(), "htop".to_string()];
+    let (out, added) = match ensurearr(&config, "environment.systemPackages", items.clone(), &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added == items);
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["(callPackage ./p.nix { })", "(f y)", "firefox", "htop", "neovim", "wget"]);
+    let (out2, added) = match ensurearr(&out, "environment.systemPackages", items, &Position::End) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(added.is_empty());
+    assert!(out2 == out);
+}
+
+#[test]
+fn remove_arr1() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+
+    // `pkgs.firefox` is `firefox` under `with pkgs;`, and its comment goes with it
+    let out = match rmarr(&config, "environment.systemPackages", vec!["pkgs.firefox".to_string(), " wget".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("with pkgs; [\n    # editors\n    neovim\n  ];"));
+    assert!(!out.contains("browser"));
+
+    let out = match rmarr(&config, "services.openssh.ports", vec!["22".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("services.openssh.ports = [ ];"));
+
+    // Items that are not in the list are returned
+    let items = vec!["neovim".to_string(), "htop".to_string(), "zsh".to_string()];
+    let (out, missing) = match removearr(&config, "environment.systemPackages", items.clone(), false) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(missing == vec!["htop", "zsh"]);
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["firefox", "wget"]);
+    let (out, missing) = removearr(&config, "environment.systemPackages", vec!["htop".to_string()], false).unwrap();
+    assert!(missing == vec!["htop"]);
+    assert!(out == config);
+
+    // and with `strict` nothing is removed
+    match removearr(&config, "environment.systemPackages", items, true) {
+        Err(Error { kind: ErrorKind::NoElement(x), .. }) => assert!(x == vec!["htop", "zsh"]),
+        _ => panic!("Removed a missing element"),
+    }
+    let e = removearr(&config, "environment.systemPackages", vec!["htop".to_string(), "zsh".to_string()], true).unwrap_err();
+    assert!(e.code() == "no_element");
+    assert!(e.to_string() == "`environment.systemPackages` has no elements `htop`, `zsh`");
+    let mut doc = Document::parse(&config).unwrap();
+    assert!(doc.rmarr("environment.systemPackages", vec!["wget".to_string(), "zsh".to_string()]).unwrap() == vec!["zsh"]);
+    assert!(doc.apply(&[Operation::ListRemove {
+        attr: "environment.systemPackages".to_string(),
+        items: vec!["neovim".to_string(), "zsh".to_string()],
+        strict: true,
+    }]).is_err());
+    assert!(getarrvals(&doc.to_string(), "environment.systemPackages").unwrap() == vec!["firefox", "neovim"]);
+
+    // `pkgs.` only names the same package under `with pkgs;`
+    let out = addtoarr(&config, "services.openssh.ports", vec!["pkgs.git".to_string()]).unwrap();
+    assert!(removearr(&out, "services.openssh.ports", vec!["git".to_string()], false).unwrap().1 == vec!["git"]);
+    assert!(removearr(&out, "services.openssh.ports", vec!["pkgs.git".to_string()], true).is_ok());
+}
+
+#[test]
+fn replace_arr1() {
+    let config = fs::read_to_string(Path::new("src/tests/lists.nix")).expect("Failed to read file");
+
+    // The element keeps its place and its comment
+    let out = match replacearr(&config, "environment.systemPackages", "pkgs.firefox", "chromium") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("with pkgs; [\n    chromium # browser\n    # editors\n    neovim\n"));
+
+    let out = match replacearr(&config, "environment.systemPackages", "neovim", "(neovim.override {\n  viAlias = true;\n})") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("    # editors\n    (neovim.override {\n      viAlias = true;\n    })\n    wget\n"));
+
+    let out = match replacearr(&config, "services.openssh.ports", "22", "2222") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("services.openssh.ports = [ 2222 ];"));
+
+    // Function applications are put in parenthesis to stay one element
+    let out = replacearr("{ a = [ x y ]; }", "a", "x", "callPackage ./p.nix { }").unwrap();
+    assert!(out == "{ a = [ (callPackage ./p.nix { }) y ]; }");
+    assert!(getarrvals(&out, "a").unwrap().len() == 2);
+
+    let mut doc = Document::parse(&config).unwrap();
+    assert!(doc.apply(&[Operation::ListReplace {
+        attr: "environment.systemPackages".to_string(),
+        old: "wget".to_string(),
+        new: "curl".to_string(),
+    }]).is_ok());
+    assert!(getarrvals(&doc.to_string(), "environment.systemPackages").unwrap() == vec!["firefox", "neovim", "curl"]);
+
+    match replacearr(&config, "environment.systemPackages", "nodejs-16_x", "nodejs_20") {
+        Err(Error { kind: ErrorKind::NoElement(x), .. }) => assert!(x == vec!["nodejs-16_x"]),
+        _ => panic!("Replaced a missing element"),
+    }
+    assert!(matches!(replacearr(&config, "environment.systemPackages", "wget", "("), Err(Error { kind: ErrorKind::InvalidValue(_), .. })));
+}
+
+#[test]
+fn concat_arr1() {
+    let config = fs::read_to_string(Path::new("src/tests/concat.nix")).expect("Failed to read file");
+
+    let r = match getarrvals(&config, "environment.systemPackages") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(r == vec!["git", "htop", "firefox", "htop"]);
+    assert!(getarrvals(&config, "fonts.packages").unwrap() == vec!["pkgs.noto-fonts"]);
+
+    let segments = match getarrsegments(&config, "networking.firewall.allowedTCPPorts") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(segments == vec![
+        ListSegment { elems: vec!["22".to_string()], condition: None },
+        ListSegment { elems: vec!["80".to_string(), "443".to_string()], condition: Some("cfg.web".to_string()) },
+        ListSegment { elems: vec!["8443".to_string()], condition: Some("cfg.web && cfg.tls".to_string()) },
+    ]);
+    assert!(getarrsegments(&config, "boot.kernelModules").unwrap()[0].condition == Some("cfg.virtualisation".to_string()));
+    // `mkIf` makes the lists under it conditional too
+    let segments = getarrsegments(&config, "services.xserver.excludePackages").unwrap();
+    assert!(segments == vec![
+        ListSegment { elems: vec!["pkgs.xterm".to_string()], condition: Some("cfg.gui".to_string()) },
+        ListSegment { elems: vec!["pkgs.xclock".to_string()], condition: Some("cfg.gui && (!cfg.tls)".to_string()) },
+    ]);
+    assert!(matches!(getarrsegments(&config, "networking.firewall"), Err(Error { kind: ErrorKind::Array, .. })));
+}
+
+#[test]
+fn concat_arr2() {
+    let config = fs::read_to_string(Path::new("src/tests/concat.nix")).expect("Failed to read file");
+
+    // Elements are added to the list that is always there
+    let out = match insertarr(&config, "environment.systemPackages", vec!["vim".to_string()], &Position::Sorted) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("with pkgs; [\n    git\n    htop\n    vim\n  ] ++ lib.optionals cfg.gui [\n    firefox\n"));
+    let out = match addtoarr(&config, "fonts.packages", vec!["pkgs.fira".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("fonts.packages = (import ./fonts.nix pkgs) ++ [ pkgs.noto-fonts pkgs.fira ];"));
+    assert!(matches!(addtoarr(&config, "boot.kernelModules", vec!["kvm".to_string()]), Err(Error { kind: ErrorKind::Array, .. })));
+    assert!(matches!(addtoarr(&config, "services.xserver.excludePackages", vec!["pkgs.xeyes".to_string()]), Err(Error { kind: ErrorKind::Array, .. })));
+    assert!(missingarr(&config, "services.xserver.excludePackages", &["pkgs.xterm".to_string()]).unwrap() == vec!["pkgs.xterm"]);
+
+    // An element that is only there under a condition is added
+    let (_, added) = ensurearr(&config, "environment.systemPackages", vec!["firefox".to_string(), "git".to_string()], &Position::End).unwrap();
+    assert!(added == vec!["firefox"]);
+
+    // Removing and replacing changes every list the element is in
+    let out = match rmarr(&config, "environment.systemPackages", vec!["htop".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["git", "firefox"]);
+    let out = match rmarr(&config, "networking.firewall.allowedTCPPorts", vec!["8443".to_string(), "22".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("allowedTCPPorts = [ ] ++ optionals cfg.web ([ 80 443 ] ++ lib.optionals cfg.tls [ ]);"));
+    let out = match replacearr(&config, "environment.systemPackages", "htop", "btop") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["git", "btop", "firefox", "btop"]);
+}
+
+#[test]
+fn concat_arr3() {
+    let config = fs::read_to_string(Path::new("src/tests/concat.nix")).expect("Failed to read file");
+
+    // A `with` inside the value only scopes the list under it
+    assert!(missingarr(&config, "environment.shells", &["pkgs.zsh".to_string(), "bashInteractive".to_string()]).unwrap() == vec!["bashInteractive"]);
+    let out = match rmarr(&config, "environment.shells", vec!["pkgs.zsh".to_string()]) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("environment.shells = [ pkgs.bashInteractive ] ++ (with pkgs; [ ]);"));
+    let out = match replacearr(&config, "environment.shells", "pkgs.zsh", "fish") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("environment.shells = [ pkgs.bashInteractive ] ++ (with pkgs; [ fish ]);"));
+    match rmarr(&config, "environment.shells", vec!["bashInteractive".to_string()]) {
+        Ok(out) => assert!(out == config),
+        Err(_) => panic!("Failed to write to file"),
+    }
+}
+
+#[test]
+fn attrpath1() {
+    let path = AttrPath::parse("networking.hosts.\"127.0.0.1\"");
+
+    // Check if quoted segments are kept together
+    assert!(path.segments() == ["networking", "hosts", "127.0.0.1"]);
+    assert!(path.to_string() == "networking.hosts.\"127.0.0.1\"");
+    assert!(AttrPath::parse("services.\"nginx\".enable").to_string() == "services.nginx.enable");
+}
+
+#[test]
+fn read_quoted1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/quoted.nix")).expect("Failed to read file");
+
+    let out = match readvalue(&config, "fileSystems.\"/boot\".fsType") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "\"vfat\"");
+
+    let out = match readvalue(&config, "networking.hosts.\"127.0.0.1\"") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "[ \"localhost\" ]");
+}
+
+#[test]
+fn read_quoted2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/quoted.nix")).expect("Failed to read file");
+
+    // Quoted keys in the file match unquoted segments in the query
+    let out = match readvalue(&config, "services.nginx.enable") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "true");
+
+    let out = match readvalue(&config, "\"foo.bar\"") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "1");
+}
+
+#[test]
+fn write_quoted1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/quoted.nix")).expect("Failed to read file");
+
+    let out = match write(&config, "fileSystems.\"/boot\".fsType", "\"ext4\"") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    let r = match readvalue(&out, "fileSystems.\"/boot\".fsType") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(r == "\"ext4\"");
+}
+
+#[test]
+fn write_quoted2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/quoted.nix")).expect("Failed to read file");
+
+    // Write value to file that does not yet exist
+    let out = match write(&config, "fileSystems.\"/boot\".options", "[ \"noatime\" ]") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    let out = match write(&out, "networking.hosts.\"10.0.0.1\"", "[ \"router\" ]") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+
+    let r = match readvalue(&out, "fileSystems.\"/boot\".options") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(r == "[ \"noatime\" ]");
+    assert!(out.contains("networking.hosts.\"10.0.0.1\" = [ \"router\" ];"));
+}
+
+#[test]
+fn deref_quoted1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/quoted.nix")).expect("Failed to read file");
+
+    let out = match deref(&config, "\"foo.bar\"") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+
+    match readvalue(&out, "\"foo.bar\"") {
+        Ok(_) => panic!("Read value that does not exist"),
+        Err(Error { kind: ErrorKind::NoAttr, .. }) => (),
+        Err(_) => panic!("Incorrect error for no attribute"),
+    }
+}
+
+#[test]
+fn read_letin1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/letin.nix")).expect("Failed to read file");
+
+    // Read value from the set returned after the let bindings
+    let out = match readvalue(&config, "networking.hostName") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "\"nixos\"");
+
+    // Let bindings are not part of the configuration
+    match readvalue(&config, "users.alice.isNormalUser") {
+        Ok(_) => panic!("Read value from let binding"),
+        Err(Error { kind: ErrorKind::NoAttr, .. }) => (),
+        Err(_) => panic!("Incorrect error type"),
+    }
+}
+
+#[test]
+fn write_letin1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/letin.nix")).expect("Failed to read file");
+
+    // Write value to file that does not yet exist
+    let out = match write(&config, "time.timeZone", "\"UTC\"") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+
+    let expectedout = r#"{ config, pkgs, lib, ... }:
+
+let
+  cfg = config.services.foo;
+  users = {
+    alice = { isNormalUser = true; };
+  };
+in
+rec {
+  networking.hostName = "nixos";
+  services.openssh.enable = true;
+  time.timeZone = "UTC";
+}
+"#;
+    assert!(out == expectedout);
+}
+
+#[test]
+fn write_letin2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/letin.nix")).expect("Failed to read file");
+
+    let out = match write(&config, "services.openssh.enable", "false") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    let out = match deref(&out, "networking.hostName") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+
+    // The let bindings must be left untouched
+    assert!(out.contains("  cfg = config.services.foo;\n"));
+    assert!(readvalue(&out, "services.openssh.enable").unwrap() == "false");
+    assert!(readvalue(&out, "networking.hostName").is_err());
+}
+
+#[test]
+fn document_apply1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    let mut doc = match Document::parse(&config) {
+        Ok(d) => d,
+        Err(_) => panic!("Failed to parse file"),
+    };
+    let ops = vec![
+        Operation::Set { attr: "boot.loader.systemd-boot.enable".to_string(), value: "false".to_string() },
+        Operation::Set { attr: "networking.hostName".to_string(), value: "\"nixos\"".to_string() },
+        Operation::Unset { attr: "programs.mtr.enable".to_string() },
+        Operation::ListAdd { attr: "environment.systemPackages".to_string(), items: vec!["git".to_string()] },
+        Operation::ListRemove { attr: "environment.systemPackages".to_string(), items: vec!["wget".to_string()], strict: true },
+    ];
+    if doc.apply(&ops).is_err() {
+        panic!("Failed to apply operations");
+    }
+    let out = doc.to_string();
+
+    // Check that the result matches applying each operation to the text
+    let mut expected = write(&config, "boot.loader.systemd-boot.enable", "false").unwrap();
+    expected = write(&expected, "networking.hostName", "\"nixos\"").unwrap();
+    expected = deref(&expected, "programs.mtr.enable").unwrap();
+    expected = addtoarr(&expected, "environment.systemPackages", vec!["git".to_string()]).unwrap();
+    expected = rmarr(&expected, "environment.systemPackages", vec!["wget".to_string()]).unwrap();
+    assert!(out == expected);
+    assert!(getarrvals(&out, "environment.systemPackages").unwrap() == vec!["vim", "firefox", "git"]);
+}
+
+#[test]
+fn document_apply2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    let mut doc = match Document::parse(&config) {
+        Ok(d) => d,
+        Err(_) => panic!("Failed to parse file"),
+    };
+    // The second operation fails, so the first one must not be applied either
+    let ops = vec![
+        Operation::Set { attr: "system.stateVersion".to_string(), value: "\"22.11\"".to_string() },
+        Operation::Unset { attr: "this.does.not.exist".to_string() },
+    ];
+    match doc.apply(&ops) {
+        Ok(_) => panic!("Removed attribute that does not exist"),
+        Err(Error { kind: ErrorKind::NoAttr, .. }) => (),
+        Err(_) => panic!("Incorrect error type"),
+    }
+    assert!(doc.to_string() == config);
+
+    // Errors name the attribute and point at the part of the file they are about
+    let ops = vec![Operation::ListAdd { attr: "system.stateVersion".to_string(), items: vec!["a".to_string()] }];
+    let e = doc.apply(&ops).unwrap_err();
+    assert!(e.attr == Some("system.stateVersion".to_string()));
+    assert!(e.span.map(|x| (x.line, x.column)) == Some((102, 25)));
+}
+
+#[test]
+fn document_write1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/format.nix")).expect("Failed to read file");
+
+    let mut doc = Document::parse(&config).unwrap();
+    doc.write("a.c", "false").unwrap();
+    doc.write("a.d.g", "\"test\"").unwrap();
+    doc.write("a.d.e", "true").unwrap();
+
+    let expectedout = r#"{
+  a = {
+    b = true;
+    d = {
+      e = true;
+      f = "hello";
+      g = "test";
+    };
+    c = false;
+  };
+}"#;
+    assert!(doc.to_string() == expectedout);
+}
+
+#[test]
+fn read_typed1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/typed.nix")).expect("Failed to read file");
+
+    // Check if strings are unescaped and literals are decoded
+    assert!(read_typed(&config, "networking.hostName").unwrap() == NixValue::String("my\"host\n".to_string()));
+    assert!(read_typed(&config, "boot.loader.timeout").unwrap() == NixValue::Int(-5));
+    assert!(read_typed(&config, "services.foo.ratio").unwrap() == NixValue::Float(0.25));
+    assert!(read_typed(&config, "services.foo.enable").unwrap() == NixValue::Bool(true));
+    assert!(read_typed(&config, "services.foo.user").unwrap() == NixValue::Null);
+    assert!(read_typed(&config, "services.foo.dataDir").unwrap() == NixValue::Path("./data".to_string()));
+    assert!(read_typed(&config, "services.foo.package").unwrap() == NixValue::Expr("pkgs.foo".to_string()));
+    assert!(read_typed(&config, "services.foo.group").unwrap() == NixValue::Expr("\"${config.users.foo}\"".to_string()));
+}
+
+#[test]
+fn read_typed2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/typed.nix")).expect("Failed to read file");
+
+    // Indented strings have their common indentation removed
+    let out = read_typed(&config, "services.foo.extraConfig").unwrap();
+    assert!(out == NixValue::String("listen 80;\nroot ${pkgs.foo};\n".to_string()));
+
+    let out = read_typed(&config, "services.foo.ports").unwrap();
+    assert!(out == NixValue::List(vec![NixValue::Int(80), NixValue::Int(443)]));
+}
+
+#[test]
+fn read_typed3() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/typed.nix")).expect("Failed to read file");
+
+    // Dotted keys are merged into nested sets
+    let out = read_typed(&config, "users.users.alice").unwrap();
+    let expected = NixValue::AttrSet(vec![
+        ("isNormalUser".to_string(), NixValue::Bool(true)),
+        (
+            "home".to_string(),
+            NixValue::AttrSet(vec![
+                ("dir".to_string(), NixValue::String("/home/alice".to_string())),
+                ("create".to_string(), NixValue::Bool(false)),
+            ]),
+        ),
+    ]);
+    assert!(out == expected);
+}
+
+#[test]
+fn write_typed1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/format.nix")).expect("Failed to read file");
+
+    // Strings from user input must be escaped
+    let val = NixValue::from("foo\"bar ${x} \\");
+    let out = match write_typed(&config, "a.c", &val) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(readvalue(&out, "a.c").unwrap() == r#""foo\"bar \${x} \\""#);
+    assert!(read_typed(&out, "a.c").unwrap() == val);
+}
+
+#[test]
+fn write_typed2() {
+    let values = vec![
+        NixValue::Null,
+        NixValue::Bool(false),
+        NixValue::Int(-3),
+        NixValue::Float(2.0),
+        NixValue::Float(1.5e300),
+        NixValue::Path("./hardware-configuration.nix".to_string()),
+        NixValue::String("".to_string()),
+        NixValue::String("tab\tand\rreturn".to_string()),
+        NixValue::String("server {\n  listen 80;\n  root ''${root}'';\n}\n".to_string()),
+        NixValue::String("no trailing\n\nnewline".to_string()),
+        NixValue::String("  indented\n  first line\n".to_string()),
+        NixValue::String("a\nb'".to_string()),
+        NixValue::String("x\n'".to_string()),
+        NixValue::String("a'${b}\nc".to_string()),
+        NixValue::String("a''${b}\nc".to_string()),
+        NixValue::from(vec![-1, 2]),
+        NixValue::List(vec![
+            NixValue::Expr("pkgs.callPackage ./foo.nix { }".to_string()),
+            NixValue::Expr("pkgs.git".to_string()),
+        ]),
+        NixValue::AttrSet(vec![
+            ("enable".to_string(), NixValue::Bool(true)),
+            ("127.0.0.1".to_string(), NixValue::from(vec!["localhost"])),
+        ]),
+        // Keys are data, never dynamic keys that would be evaluated
+        NixValue::AttrSet(vec![
+            ("${builtins.abort \"x\"}".to_string(), NixValue::Int(1)),
+            ("${a".to_string(), NixValue::Int(2)),
+            ("a b".to_string(), NixValue::Int(3)),
+            ("".to_string(), NixValue::Int(4)),
+            ("with\nline".to_string(), NixValue::Int(5)),
+        ]),
+    ];
+
+    // Check that every value is read back exactly as it was written
+    for val in values {
+        let out = match write_typed("{\n}", "x", &val) {
+            Ok(s) => s,
+            Err(_) => panic!("Failed to write to file"),
+        };
+        let r = match read_typed(&out, "x") {
+            Ok(s) => s,
+            Err(_) => panic!("Failed to read value"),
+        };
+        assert!(r == val, "{:?} was read back as {:?}", val, r);
+    }
+}
+
+#[test]
+fn write_typed3() {
+    // Multiline text is written as an indented string
+    let val = NixValue::String("line one\nline two\n".to_string());
+    let out = write_typed("{\n}", "x", &val).unwrap();
+    assert!(out == "{\n  x = ''\n    line one\n    line two\n  '';\n}");
+}
+
+#[test]
+fn write_typed4() {
+    // Nix has no literals for these floats
+    for x in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        let val = NixValue::List(vec![NixValue::Float(x)]);
+        match write_typed("{\n}", "x", &val) {
+            Err(Error { kind: ErrorKind::Unwritable(_), .. }) => {}
+            _ => panic!("Wrote {:?}", x),
+        }
+    }
+    // The value is not in any file, so there is nothing to point at
+    let e = write_typed("{\n}", "x", &NixValue::Float(f64::NAN)).unwrap_err();
+    assert!(e.code() == "unwritable_value");
+    assert!(e.span.is_none());
+    assert!(e.to_string() == "cannot set `x` to NaN, Nix has no literal for it");
+}
+
+#[test]
+fn read_comments1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/comments.nix")).expect("Failed to read file");
+
+    let out = match readcomments(&config, "boot.kernelPackages") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read comments"),
+    };
+    assert!(out.leading.as_deref() == Some("Keep the old kernel until the wifi driver is fixed\nhttps://example.com/issue/123"));
+    assert!(out.trailing.is_none());
+
+    // A trailing comment does not belong to the binding on the next line
+    let out = readcomments(&config, "networking.hostName").unwrap();
+    assert!(out.trailing.as_deref() == Some("Define your hostname."));
+    assert!(readcomments(&config, "time.timeZone").unwrap() == Comments::default());
+
+    let out = readcomments(&config, "services.openssh.enable").unwrap();
+    assert!(out.leading.as_deref() == Some("Only reachable from the VPN"));
+}
+
+#[test]
+fn write_comment1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/comments.nix")).expect("Failed to read file");
+
+    // Replace an existing comment
+    let out = match setcomment(&config, "boot.kernelPackages", Some("Latest is fine now")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write comment"),
+    };
+    assert!(out.contains("\n\n  # Latest is fine now\n  boot.kernelPackages"));
+    assert!(!out.contains("wifi driver"));
+
+    // Add one where there was none, keeping the indentation
+    let out = setcomment(&config, "services.openssh.enable", None).unwrap();
+    let out = setcomment(&out, "services.openssh.enable", Some("Needed for\ndeploys")).unwrap();
+    assert!(out.contains("  services.openssh = {\n    # Needed for\n    # deploys\n    enable = true;"));
+    assert!(!out.contains("VPN"));
+
+    match setcomment(&config, "services.nginx.enable", Some("x")) {
+        Ok(_) => panic!("Comment written to missing attribute"),
+        Err(Error { kind: ErrorKind::NoAttr, .. }) => {}
+        Err(_) => panic!("Wrong error"),
+    }
+}
+
+#[test]
+fn deref_comment1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/comments.nix")).expect("Failed to read file");
+
+    // The comments go together with the binding they document
+    let out = match deref(&config, "boot.kernelPackages") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to remove attribute"),
+    };
+    assert!(!out.contains("wifi driver") && !out.contains("issue/123"));
+    assert!(out.contains("./hardware-configuration.nix ];\n\n  networking.hostName"));
+
+    let out = deref(&config, "networking.hostName").unwrap();
+    assert!(!out.contains("Define your hostname"));
+    assert!(out.contains("linuxPackages_5_15;\n  time.timeZone"));
+}
+
+#[test]
+fn write_indent1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/indent.nix")).expect("Failed to read file");
+
+    // New bindings and list elements follow the four space indentation of the file
+    let out = match write(&config, "services.foo.port", "80") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("    services.foo = {\n        enable = true;\n        port = 80;\n    };"));
+
+    let out = addtoarr(&config, "environment.systemPackages", vec!["htop".to_string()]).unwrap();
+    assert!(out.contains("[\n        git\n        htop\n    ];"));
+    let out = addtoarr(&config, "fonts.fonts", vec!["noto".to_string()]).unwrap();
+    assert!(out.contains("\n    fonts.fonts = [\n        noto\n    ];\n}"));
+
+    // Multiline values are moved to the indentation of the binding
+    let out = write(&config, "services.bar", "{\n  a = [\n    1\n  ];\n}").unwrap();
+    assert!(out.contains("\n    services.bar = {\n        a = [\n            1\n        ];\n    };\n"));
+
+    let val = NixValue::String("a\n  b\n".to_string());
+    let out = write_typed(&config, "services.foo.text", &val).unwrap();
+    assert!(out.contains("        text = ''\n            a\n              b\n        '';\n    };"));
+    assert!(read_typed(&out, "services.foo.text").unwrap() == val);
+}
+
+#[test]
+fn write_indent2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/indent.nix")).expect("Failed to read file");
+    let config = config.replace("    ", "\t");
+
+    let out = match write(&config, "services.foo.port", "80") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("\tservices.foo = {\n\t\tenable = true;\n\t\tport = 80;\n\t};"));
+
+    // Tabs are not stripped from indented strings, so the contents keep their spaces
+    let val = NixValue::String("a\n  b\n".to_string());
+    let out = write_typed(&config, "users.motd", &val).unwrap();
+    assert!(read_typed(&out, "users.motd").unwrap() == val);
+}
+
+#[test]
+fn read_wrapped1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/module.nix")).expect("Failed to read file");
+
+    // Options inside `mkIf` and `mkMerge` are found. Only typed and wrapped reads
+    // return the inner value.
+    let out = match readvalue(&config, "config.services.foo.port") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read value"),
+    };
+    assert!(out == "lib.mkDefault 8080");
+    assert!(read_typed(&config, "config.services.foo.port").unwrap() == NixValue::Int(8080));
+    assert!(getarrvals(&config, "config.networking.firewall.allowedTCPPorts").unwrap() == vec!["cfg.port"]);
+
+    let (val, wrappers) = readwrapped(&config, "config.services.foo.port").unwrap();
+    assert!(val == "8080");
+    assert!(wrappers == vec![Wrapper::If("cfg.enable".to_string()), Wrapper::Merge, Wrapper::Default]);
+
+    let (_, wrappers) = readwrapped(&config, "config.networking.firewall.allowedTCPPorts").unwrap();
+    assert!(wrappers[2] == Wrapper::If("cfg.openFirewall".to_string()));
+    assert!(wrappers[3] == Wrapper::Override("50".to_string()));
+    assert!(wrappers[3].to_string() == "mkOverride 50");
+}
+
+#[test]
+fn read_wrapped2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/module.nix")).expect("Failed to read file");
+
+    let map = match get_collection(config) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to collect attributes"),
+    };
+    assert!(map.get("config.services.foo.port").unwrap() == "8080");
+    assert!(map.get("config.networking.firewall.allowedTCPPorts").unwrap() == "[ cfg.port ]");
+    assert!(map.contains_key("config.environment.systemPackages"));
+}
+
+#[test]
+fn read_location1() {
+    let config = fs::read_to_string(Path::new("src/tests/comments.nix")).unwrap();
+    let loc = match readlocation(&config, "networking.hostName") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read location"),
+    };
+    assert!(&config[loc.key.start..loc.key.end] == "networking.hostName");
+    assert!(&config[loc.value.start..loc.value.end] == "\"nixos\"");
+    assert!(&config[loc.binding.start..loc.binding.end] == "networking.hostName = \"nixos\";");
+    assert!((loc.key.line, loc.key.column) == (9, 3));
+    assert!((loc.value.line, loc.value.column, loc.value.endcolumn) == (9, 25, 32));
+    assert!(loc.key.file.is_none());
+
+    // Nested bindings are found inside their sets, and whole sets span several lines
+    let loc = readlocation(&config, "services.openssh.enable").unwrap();
+    assert!((loc.key.line, loc.key.column) == (14, 5));
+    let loc = readlocation(&config, "services.openssh").unwrap();
+    assert!((loc.binding.line, loc.binding.endline, loc.binding.endcolumn) == (12, 15, 5));
+
+    // `boot` only exists as part of a longer key
+    assert!(matches!(readlocation(&config, "boot"), Err(Error { kind: ErrorKind::NoAttr, .. })));
+}
+
+#[test]
+fn read_location2() {
+    let ws = match Workspace::open(Path::new("src/tests/workspace/configuration.nix")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to open workspace"),
+    };
+    let loc = match ws.location("services.nginx.enable") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to read location"),
+    };
+    let file = PathBuf::from("src/tests/workspace/modules/web/default.nix");
+    assert!(loc.value.file == Some(file.clone()));
+    assert!((loc.key.line, loc.key.column, loc.value.column) == (7, 5, 29));
+    let config = ws.source(&file).unwrap();
+    assert!(&config[loc.value.start..loc.value.end] == "true");
+}
+
+#[test]
+fn write_inner1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/priority.nix")).expect("Failed to read file");
+
+    // Only the value inside the wrapper is replaced
+    let out = match write_inner(&config, "services.foo.enable", "false") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("  services.foo.enable = lib.mkForce false;\n"));
+
+    let out = write_inner(&config, "services.foo.port", "lib.max 80 8080").unwrap();
+    assert!(out.contains("  services.foo.port = lib.mkOverride 50 (lib.max 80 8080);\n"));
+    let out = write_inner(&config, "config.a", "[ 2 ]").unwrap();
+    assert!(out.contains("    a = lib.mkDefault [ 2 ];\n"));
+
+    // A plain write still replaces the whole value
+    let out = write(&config, "services.foo.enable", "false").unwrap();
+    assert!(out.contains("  services.foo.enable = false;\n"));
+}
+
+#[test]
+fn write_wrapper1() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/priority.nix")).expect("Failed to read file");
+
+    let out = match addwrapper(&config, "services.foo.user", &Wrapper::Force) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("  services.foo.user = lib.mkForce \"foo\";\n"));
+
+    // A new priority replaces the old one
+    let out = addwrapper(&config, "services.foo.port", &Wrapper::Override("10".to_string())).unwrap();
+    assert!(out.contains("  services.foo.port = lib.mkOverride 10 (8000 + 80);\n"));
+    let out = addwrapper(&config, "services.foo.enable", &Wrapper::Default).unwrap();
+    assert!(out.contains("  services.foo.enable = lib.mkDefault true;\n"));
+
+    match addwrapper(&config, "services.bar", &Wrapper::Force) {
+        Ok(_) => panic!("Wrapped a missing attribute"),
+        Err(Error { kind: ErrorKind::NoAttr, .. }) => {}
+        Err(_) => panic!("Wrong error"),
+    }
+
+    // Files using `with lib;` get the bare function name
+    let out = addwrapper("{\n  a = mkDefault 1;\n  b = 2;\n}", "b", &Wrapper::Force).unwrap();
+    assert!(out == "{\n  a = mkDefault 1;\n  b = mkForce 2;\n}");
+}
+
+#[test]
+fn write_wrapper2() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/priority.nix")).expect("Failed to read file");
+
+    let out = match rmwrapper(&config, "services.foo.port") {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("  services.foo.port = 8000 + 80;\n"));
+    let out = rmwrapper(&config, "config.a").unwrap();
+    assert!(out.contains("    a = [\n      1\n    ];\n"));
+
+    // Values without a priority are left alone
+    assert!(rmwrapper(&config, "services.foo.user").unwrap() == config);
+
+    let mut doc = Document::parse(&config).unwrap();
+    let ops = vec![
+        Operation::Wrap { attr: "services.foo.user".to_string(), wrapper: Wrapper::Default },
+        Operation::SetInner { attr: "services.foo.user".to_string(), value: "\"bar\"".to_string() },
+        Operation::Unwrap { attr: "services.foo.enable".to_string() },
+    ];
+    doc.apply(&ops).unwrap();
+    let out = doc.to_string();
+    assert!(out.contains("  services.foo.user = lib.mkDefault \"bar\";\n"));
+    assert!(out.contains("  services.foo.enable = true;\n"));
+}
+
+#[test]
+fn write_wrapper3() {
+    let config =
+        fs::read_to_string(Path::new("src/tests/configuration.nix")).expect("Failed to read file");
+
+    // `lib` is added to the arguments when the file does not take it
+    let out = match addwrapper(&config, "system.stateVersion", &Wrapper::Default) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to write to file"),
+    };
+    assert!(out.contains("{ config, lib, pkgs, ... }:"));
+    assert!(out.contains("  system.stateVersion = lib.mkDefault \"22.05\";"));
+
+    let out = addwrapper("{ pkgs, ... }:\n{\n  a = 1;\n}", "a", &Wrapper::Force).unwrap();
+    assert!(out == "{ lib, pkgs, ... }:\n{\n  a = lib.mkForce 1;\n}");
+    let out = addwrapper("args:\n{\n  a = 1;\n}", "a", &Wrapper::Force).unwrap();
+    assert!(out == "{ lib, ... }@args:\n{\n  a = lib.mkForce 1;\n}");
+    let out = addwrapper("{\n  a = 1;\n}", "a", &Wrapper::Force).unwrap();
+    assert!(out == "{ lib, ... }:\n\n{\n  a = lib.mkForce 1;\n}");
+
+    // Files that already have `lib` are left alone
+    let out = addwrapper("{ lib, ... }:\n{\n  a = 1;\n}", "a", &Wrapper::Force).unwrap();
+    assert!(out == "{ lib, ... }:\n{\n  a = lib.mkForce 1;\n}");
+    let out = addwrapper("let\n  lib = import <nixpkgs/lib>;\nin\n{\n  a = 1;\n}", "a", &Wrapper::Force).unwrap();
+    assert!(out == "let\n  lib = import <nixpkgs/lib>;\nin\n{\n  a = lib.mkForce 1;\n}");
+}
+
+#[test]
+fn workspace_read1() {
+    let ws = match Workspace::open(Path::new("src/tests/workspace/configuration.nix")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to open workspace"),
+    };
+    // Directories import their default.nix, and the import cycle is only followed once
+    assert!(ws.files().len() == 4);
+    assert!(ws.files()[3] == Path::new("src/tests/workspace/modules/web/default.nix"));
+
+    assert!(ws.locate("networking.hostName") == vec![PathBuf::from("src/tests/workspace/modules/networking.nix")]);
+    let (file, val) = ws.readvalue("networking.hostName").unwrap();
+    assert!(file == Path::new("src/tests/workspace/modules/networking.nix"));
+    assert!(val == "\"nixos\"");
+
+    // Modules declaring options keep their settings under `config`
+    let (file, attr) = ws.find("services.nginx.enable").unwrap();
+    assert!(file == Path::new("src/tests/workspace/modules/web/default.nix"));
+    assert!(attr == "config.services.nginx.enable");
+    assert!(ws.locate("services.openssh.enable").is_empty());
+}
+
+#[test]
+fn workspace_read2() {
+    let ws = match Workspace::open(Path::new("src/tests/skipped/configuration.nix")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to open workspace"),
+    };
+    // Files with syntax errors are skipped together with what only they import
+    assert!(ws.files() == vec![Path::new("src/tests/skipped/configuration.nix"), Path::new("src/tests/skipped/users.nix")]);
+    assert!(ws.skipped().len() == 1);
+    let e = &ws.skipped()[0];
+    assert!(e.file == Some(PathBuf::from("src/tests/skipped/broken.nix")));
+    assert!(e.code() == "parse_error");
+    assert!(e.span.as_ref().map(|x| x.line) == Some(7));
+    assert!(ws.locate("fonts.packages").is_empty());
+}
+
+#[test]
+fn workspace_read3() {
+    let ws = match Workspace::open(Path::new("src/tests/imports/configuration.nix")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to open workspace"),
+    };
+    // Paths given as arguments to an imported function are not imports themselves
+    assert!(ws.files() == vec![
+        Path::new("src/tests/imports/configuration.nix"),
+        Path::new("src/tests/imports/a.nix"),
+        Path::new("src/tests/imports/c.nix"),
+        Path::new("src/tests/imports/d.nix"),
+    ]);
+    assert!(ws.locate("time.timeZone").is_empty());
+    assert!(ws.locate("services.openssh.enable") == vec![PathBuf::from("src/tests/imports/d.nix")]);
+}
+
+#[test]
+fn workspace_write1() {
+    let mut ws = match Workspace::open(Path::new("src/tests/workspace/configuration.nix")) {
+        Ok(s) => s,
+        Err(_) => panic!("Failed to open workspace"),
+    };
+    let file = ws.write("networking.hostName", "\"box\"").unwrap();
+    assert!(file == Path::new("src/tests/workspace/modules/networking.nix"));
+
+    let ops = vec![
+        // New attributes go next to the ones sharing their path
+        Operation::Set { attr: "services.nginx.recommendedGzipSettings".to_string(), value: "true".to_string() },
+        Operation::Set { attr: "console.keyMap".to_string(), value: "\"us\"".to_string() },
+    ];
+    ws.apply(&ops).unwrap();
+
+    let changed = ws.changed();
+    assert!(changed.len() == 3);
+    assert!(changed[0].0 == Path::new("src/tests/workspace/configuration.nix"));
+    assert!(changed[0].1.contains("  console.keyMap = \"us\";\n"));
+    assert!(changed[1].1.contains("  networking.hostName = \"box\";\n"));
+    assert!(changed[2].1.contains("    services.nginx.enable = true;\n    services.nginx.recommendedGzipSettings = true;\n  };"));
+
+    // Nothing is changed if an operation fails
+    let ops = vec![
+        Operation::Unset { attr: "time.timeZone".to_string() },
+        Operation::Unset { attr: "services.openssh.enable".to_string() },
+    ];
+    assert!(ws.apply(&ops).is_err());
+    assert!(ws.changed().len() == 3);
+    assert!(ws.readvalue("time.timeZone").is_ok());
+}
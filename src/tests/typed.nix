@@ -0,0 +1,22 @@
+{ pkgs, ... }:
+
+{
+  networking.hostName = "my\"host\n";
+  boot.loader.timeout = -5;
+  services.foo.ratio = 0.25;
+  services.foo.enable = true;
+  services.foo.package = pkgs.foo;
+  services.foo.dataDir = ./data;
+  services.foo.extraConfig = ''
+    listen 80;
+    root ''${pkgs.foo};
+  '';
+  services.foo.user = null;
+  services.foo.group = "${config.users.foo}";
+  services.foo.ports = [ 80 (443) ];
+  users.users.alice = {
+    isNormalUser = true;
+    home.dir = "/home/alice";
+    home.create = false;
+  };
+}
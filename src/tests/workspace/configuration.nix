@@ -0,0 +1,13 @@
+{ config, pkgs, ... }:
+
+{
+  imports = [
+    ./hardware-configuration.nix
+    ./modules/networking.nix
+    ./modules/web
+    <nixpkgs/nixos/modules/profiles/minimal.nix>
+  ];
+
+  time.timeZone = "Europe/Amsterdam";
+  system.stateVersion = "22.05";
+}
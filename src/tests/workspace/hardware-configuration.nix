@@ -0,0 +1,8 @@
+{ config, lib, modulesPath, ... }:
+
+{
+  imports = [ (modulesPath + "/installer/scan/not-detected.nix") ];
+
+  boot.initrd.availableKernelModules = [ "xhci_pci" "ahci" ];
+  fileSystems."/".device = "/dev/disk/by-label/nixos";
+}
@@ -0,0 +1,8 @@
+{ ... }:
+
+{
+  imports = [ ../configuration.nix ];
+
+  networking.hostName = "nixos";
+  networking.firewall.allowedTCPPorts = [ 22 ];
+}
@@ -0,0 +1,9 @@
+{ config, lib, ... }:
+
+{
+  options.web.enable = lib.mkEnableOption "web";
+
+  config = lib.mkIf config.web.enable {
+    services.nginx.enable = true;
+  };
+}
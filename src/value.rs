@@ -0,0 +1,372 @@
+use rnix::{
+    types::{Str, TypedNode},
+    StrPart, SyntaxKind, SyntaxNode,
+};
+
+use crate::{
+    errors::{Error, ErrorKind},
+    parse::{quotesegment, AttrPath},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NixValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Path(String),
+    List(Vec<NixValue>),
+    // Attributes in the order they are written, with dotted keys merged into nested sets
+    AttrSet(Vec<(String, NixValue)>),
+    // Anything that can not be decoded without evaluating it, as written in the file
+    Expr(String),
+}
+
+impl NixValue {
+    pub fn from_node(node: &SyntaxNode) -> Self {
+        match node.kind() {
+            SyntaxKind::NODE_IDENT => match node.text().to_string().as_str() {
+                "null" => NixValue::Null,
+                "true" => NixValue::Bool(true),
+                "false" => NixValue::Bool(false),
+                _ => NixValue::Expr(node.to_string()),
+            },
+            SyntaxKind::NODE_LITERAL => fromliteral(node, false),
+            SyntaxKind::NODE_UNARY_OP => match node.first_child() {
+                Some(x)
+                    if x.kind() == SyntaxKind::NODE_LITERAL
+                        && node.first_token().map(|t| t.kind()) == Some(SyntaxKind::TOKEN_SUB) =>
+                {
+                    fromliteral(&x, true)
+                }
+                _ => NixValue::Expr(node.to_string()),
+            },
+            SyntaxKind::NODE_STRING => match fromstring(node) {
+                Some(s) => NixValue::String(s),
+                None => NixValue::Expr(node.to_string()),
+            },
+            SyntaxKind::NODE_PAREN => match node.first_child() {
+                Some(x) => NixValue::from_node(&x),
+                None => NixValue::Expr(node.to_string()),
+            },
+            SyntaxKind::NODE_LIST => {
+                NixValue::List(node.children().map(|x| NixValue::from_node(&x)).collect())
+            }
+            SyntaxKind::NODE_ATTR_SET => {
+                let mut attrs = vec![];
+                for child in node.children() {
+                    match child.kind() {
+                        SyntaxKind::NODE_KEY_VALUE => {
+                            let mut children = child.children();
+                            if let (Some(key), Some(value)) = (children.next(), children.next()) {
+                                let key = AttrPath::from_key(&key);
+                                insertattr(&mut attrs, key.segments(), NixValue::from_node(&value));
+                            }
+                        }
+                        SyntaxKind::NODE_INHERIT => {
+                            let from = child
+                                .children()
+                                .find(|x| x.kind() == SyntaxKind::NODE_INHERIT_FROM)
+                                .and_then(|x| x.first_child());
+                            for ident in child
+                                .children()
+                                .filter(|x| x.kind() == SyntaxKind::NODE_IDENT)
+                            {
+                                let value = match &from {
+                                    Some(f) => format!("{}.{}", f, ident),
+                                    None => ident.to_string(),
+                                };
+                                attrs.push((ident.to_string(), NixValue::Expr(value)));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                NixValue::AttrSet(attrs)
+            }
+            _ => NixValue::Expr(node.to_string()),
+        }
+    }
+}
+
+impl NixValue {
+    // Render the value as Nix source, quoting and escaping strings as needed. Fails
+    // for floats Nix has no literal for.
+    pub fn to_nix(&self) -> Result<String, Error> {
+        Ok(match self {
+            NixValue::Null => "null".to_string(),
+            NixValue::Bool(b) => b.to_string(),
+            NixValue::Int(i) => i.to_string(),
+            NixValue::Float(x) => tofloat(*x)?,
+            NixValue::String(s) => tostring(s),
+            NixValue::Path(p) => topath(p),
+            NixValue::List(l) => {
+                if l.is_empty() {
+                    return Ok("[ ]".to_string());
+                }
+                let items = l.iter().map(tolistitem).collect::<Result<Vec<_>, _>>()?;
+                format!("[ {} ]", items.join(" "))
+            }
+            NixValue::AttrSet(a) => {
+                if a.is_empty() {
+                    return Ok("{ }".to_string());
+                }
+                let mut attrs = vec![];
+                for (k, v) in a {
+                    attrs.push(format!("{} = {};", tokey(k), v.to_nix()?));
+                }
+                format!("{{ {} }}", attrs.join(" "))
+            }
+            NixValue::Expr(e) => e.to_string(),
+        })
+    }
+}
+
+impl From<bool> for NixValue {
+    fn from(val: bool) -> Self {
+        NixValue::Bool(val)
+    }
+}
+
+impl From<i64> for NixValue {
+    fn from(val: i64) -> Self {
+        NixValue::Int(val)
+    }
+}
+
+impl From<f64> for NixValue {
+    fn from(val: f64) -> Self {
+        NixValue::Float(val)
+    }
+}
+
+impl From<&str> for NixValue {
+    fn from(val: &str) -> Self {
+        NixValue::String(val.to_string())
+    }
+}
+
+impl From<String> for NixValue {
+    fn from(val: String) -> Self {
+        NixValue::String(val)
+    }
+}
+
+impl<T: Into<NixValue>> From<Vec<T>> for NixValue {
+    fn from(val: Vec<T>) -> Self {
+        NixValue::List(val.into_iter().map(|x| x.into()).collect())
+    }
+}
+
+impl<T: Into<NixValue>> From<Option<T>> for NixValue {
+    fn from(val: Option<T>) -> Self {
+        match val {
+            Some(x) => x.into(),
+            None => NixValue::Null,
+        }
+    }
+}
+
+fn tofloat(x: f64) -> Result<String, Error> {
+    if !x.is_finite() {
+        return Err(Error::new(ErrorKind::Unwritable(format!("{:?}", x))));
+    }
+    // Nix floats always need a decimal point
+    let out = format!("{:?}", x);
+    Ok(if out.contains('.') {
+        out
+    } else if let Some(i) = out.find('e') {
+        format!("{}.0{}", &out[..i], &out[i..])
+    } else {
+        format!("{}.0", out)
+    })
+}
+
+// A Nix string literal for `s`
+pub fn tostring(s: &str) -> String {
+    // A quote right before the closing `''` would be read as an escape, and one
+    // right before the `''${` escape would make it `'''` followed by a live `${`
+    if s.contains('\n') && !s.contains('\r') && !s.ends_with('\'') && !s.contains("'${") && indentable(s) {
+        // Indented strings strip the common indentation again when they are read.
+        // The lines are indented from column 0 and moved into place when written.
+        let mut out = String::from("''\n");
+        let lines = s.split('\n').collect::<Vec<_>>();
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.replace("''", "'''").replace("${", "''${");
+            if i == lines.len() - 1 {
+                if !line.is_empty() {
+                    out.push_str(&format!("  {}", line));
+                }
+            } else if line.is_empty() {
+                out.push('\n');
+            } else {
+                out.push_str(&format!("  {}\n", line));
+            }
+        }
+        out.push_str("''");
+        out
+    } else {
+        quoted(s)
+    }
+}
+
+// `s` as a double quoted string
+fn quoted(s: &str) -> String {
+    let mut out = String::from("\"");
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// A key of a typed attribute set. Keys are data, so one like `${x}` is quoted
+// instead of being written as a dynamic key.
+fn tokey(k: &str) -> String {
+    let key = quotesegment(k);
+    if key == k && !k.starts_with("${") {
+        key
+    } else {
+        quoted(k)
+    }
+}
+
+// Whether an indented string gives back exactly `s` once its indentation is removed
+fn indentable(s: &str) -> bool {
+    let lines = s.split('\n').collect::<Vec<_>>();
+    let last = lines[lines.len() - 1];
+    // A trailing line of only whitespace is dropped by Nix
+    if !last.is_empty() && last.trim().is_empty() {
+        return false;
+    }
+    lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .any(|l| !l.starts_with(char::is_whitespace))
+}
+
+fn topath(p: &str) -> String {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._-+~/".contains(c);
+    let search = p.starts_with('<') && p.ends_with('>');
+    if search || (p.contains('/') && !p.ends_with('/') && !p.contains("//") && p.chars().all(valid)) {
+        p.to_string()
+    } else if let Some(rest) = p.strip_prefix('/') {
+        format!("(/. + {})", tostring(&format!("/{}", rest)))
+    } else {
+        let rest = p.strip_prefix("./").unwrap_or(p);
+        format!("(./. + {})", tostring(&format!("/{}", rest)))
+    }
+}
+
+fn tolistitem(val: &NixValue) -> Result<String, Error> {
+    let out = val.to_nix()?;
+    let wrap = match val {
+        NixValue::Int(i) => *i < 0,
+        NixValue::Float(x) => *x < 0.0,
+        NixValue::Expr(e) => !isatom(e),
+        _ => false,
+    };
+    Ok(if wrap { format!("({})", out) } else { out })
+}
+
+// Nix source for `val` that can be used as a function argument or list element
+pub(crate) fn argument(val: &str) -> String {
+    if isatom(val) {
+        val.to_string()
+    } else {
+        format!("({})", val.trim())
+    }
+}
+
+// Function applications and operators need parenthesis inside a list or as an argument
+fn isatom(val: &str) -> bool {
+    match rnix::parse(val).node().first_child() {
+        Some(x) => matches!(
+            x.kind(),
+            SyntaxKind::NODE_IDENT
+                | SyntaxKind::NODE_SELECT
+                | SyntaxKind::NODE_LITERAL
+                | SyntaxKind::NODE_STRING
+                | SyntaxKind::NODE_LIST
+                | SyntaxKind::NODE_ATTR_SET
+                | SyntaxKind::NODE_PAREN
+        ),
+        None => false,
+    }
+}
+
+fn fromliteral(node: &SyntaxNode, negate: bool) -> NixValue {
+    let token = match node.first_token() {
+        Some(x) => x,
+        None => return NixValue::Expr(node.to_string()),
+    };
+    let text = if negate {
+        format!("-{}", token.text())
+    } else {
+        token.text().to_string()
+    };
+    match token.kind() {
+        SyntaxKind::TOKEN_INTEGER => match text.parse() {
+            Ok(x) => NixValue::Int(x),
+            Err(_) => NixValue::Expr(text),
+        },
+        SyntaxKind::TOKEN_FLOAT => match text.parse() {
+            Ok(x) => NixValue::Float(x),
+            Err(_) => NixValue::Expr(text),
+        },
+        SyntaxKind::TOKEN_PATH if !negate => NixValue::Path(text),
+        SyntaxKind::TOKEN_URI if !negate => NixValue::String(text),
+        _ => NixValue::Expr(node.to_string()),
+    }
+}
+
+// Unescaped string contents, or None if the string has interpolations
+fn fromstring(node: &SyntaxNode) -> Option<String> {
+    let mut out = String::new();
+    for part in Str::cast(node.clone())?.parts() {
+        match part {
+            StrPart::Literal(l) => out.push_str(&l),
+            StrPart::Ast(_) => return None,
+        }
+    }
+    Some(out)
+}
+
+fn insertattr(attrs: &mut Vec<(String, NixValue)>, key: &[String], value: NixValue) {
+    if key.is_empty() {
+        return;
+    }
+    if key.len() == 1 {
+        // `a = { ... }; a.b = ...;` style bindings are merged together
+        if let NixValue::AttrSet(new) = &value {
+            if let Some((_, NixValue::AttrSet(existing))) =
+                attrs.iter_mut().find(|(k, _)| k == &key[0])
+            {
+                for (k, v) in new {
+                    insertattr(existing, std::slice::from_ref(k), v.clone());
+                }
+                return;
+            }
+        }
+        attrs.push((key[0].clone(), value));
+        return;
+    }
+    match attrs.iter_mut().find(|(k, _)| k == &key[0]) {
+        Some((_, NixValue::AttrSet(existing))) => insertattr(existing, &key[1..], value),
+        _ => {
+            let mut sub = vec![];
+            insertattr(&mut sub, &key[1..], value);
+            attrs.push((key[0].clone(), NixValue::AttrSet(sub)));
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rnix::{SyntaxKind, SyntaxNode};
+
+use crate::{
+    document::{Document, Operation},
+    errors::{Error, ErrorKind},
+    location::AttrLocation,
+    parse::{findattr, getcfgbase, AttrPath},
+    read::{listsegments, missingarr_node, readlocation, readvalue},
+    write::rmarr_node,
+};
+
+// A configuration together with every module it imports, directly or through
+// other modules. Edits go to the module that defines the attribute.
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    // Modules in the order they are imported, starting with the entry point
+    modules: Vec<(PathBuf, Document)>,
+    changed: HashSet<usize>,
+    // Imported files that could not be parsed, whose own imports are not followed
+    skipped: Vec<Error>,
+}
+
+impl Workspace {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut ws = Workspace {
+            modules: vec![],
+            changed: HashSet::new(),
+            skipped: vec![],
+        };
+        let mut seen = HashSet::new();
+        ws.load(path, &mut seen)?;
+        if ws.modules.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse '{}'", path.display()),
+            ));
+        }
+        Ok(ws)
+    }
+
+    fn load(&mut self, path: &Path, seen: &mut HashSet<PathBuf>) -> io::Result<()> {
+        // Importing a directory imports its default.nix
+        let path = if path.is_dir() {
+            path.join("default.nix")
+        } else {
+            path.to_path_buf()
+        };
+        if !seen.insert(fs::canonicalize(&path)?) {
+            return Ok(());
+        }
+        let f = fs::read_to_string(&path)?;
+        // Modules that are not an attribute set can not be edited, so they are skipped
+        let doc = match Document::parse(&f) {
+            Ok(x) => x,
+            Err(e) => {
+                self.skipped.push(e.withfile(&path));
+                return Ok(());
+            }
+        };
+        let imports = getimports(doc.node(), &path);
+        self.modules.push((path, doc));
+        for import in imports {
+            if import.exists() {
+                self.load(&import, seen)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Imported files that were skipped as they have syntax errors or are not an
+    // attribute set. Files imported only by them are missing from the workspace too.
+    pub fn skipped(&self) -> &[Error] {
+        &self.skipped
+    }
+
+    // The configuration file the workspace was opened with
+    pub fn entry(&self) -> &Path {
+        self.modules[0].0.as_path()
+    }
+
+    pub fn files(&self) -> Vec<&Path> {
+        self.modules.iter().map(|(p, _)| p.as_path()).collect()
+    }
+
+    pub fn source(&self, path: &Path) -> Option<String> {
+        self.modules
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, doc)| doc.to_string())
+    }
+
+    // Files that were modified by `apply`, with their new contents
+    pub fn changed(&self) -> Vec<(&Path, String)> {
+        let mut out = self.changed.iter().copied().collect::<Vec<_>>();
+        out.sort_unstable();
+        out.into_iter()
+            .map(|i| (self.modules[i].0.as_path(), self.modules[i].1.to_string()))
+            .collect()
+    }
+
+    // Every file that defines `query`
+    pub fn locate(&self, query: &str) -> Vec<PathBuf> {
+        self.modules
+            .iter()
+            .filter(|(_, doc)| resolve(doc.node(), query).is_some())
+            .map(|(p, _)| p.clone())
+            .collect()
+    }
+
+    // The first file that defines `query`, and the attribute as it is written
+    // there, which is under `config` in modules that declare options
+    pub fn find(&self, query: &str) -> Option<(PathBuf, String)> {
+        self.modules.iter().find_map(|(p, doc)| {
+            resolve(doc.node(), query).map(|attr| (p.clone(), attr))
+        })
+    }
+
+    pub fn readvalue(&self, query: &str) -> Result<(PathBuf, String), Error> {
+        let (path, attr, f) = self.lookup(query)?;
+        match readvalue(&f, &attr) {
+            Ok(x) => Ok((path, x)),
+            Err(e) => Err(e.withfile(&path)),
+        }
+    }
+
+    // Where `query` is defined, with the file filled in
+    pub fn location(&self, query: &str) -> Result<AttrLocation, Error> {
+        let (path, attr, f) = self.lookup(query)?;
+        match readlocation(&f, &attr) {
+            Ok(mut x) => {
+                x.setfile(path);
+                Ok(x)
+            }
+            Err(e) => Err(e.withfile(&path)),
+        }
+    }
+
+    // The items an `Operation::ListEnsure` would add to `query`
+    pub fn missingarr(&self, query: &str, items: &[String]) -> Result<Vec<String>, Error> {
+        let (i, attr) = target(&self.modules, query);
+        let (path, doc) = &self.modules[i];
+        missingarr_node(doc.node(), &attr, items).map_err(|e| e.withfile(path))
+    }
+
+    // The items an `Operation::ListRemove` would not find in `query`
+    pub fn absentarr(&self, query: &str, items: &[String]) -> Result<Vec<String>, Error> {
+        let (i, attr) = target(&self.modules, query);
+        let (path, doc) = &self.modules[i];
+        match rmarr_node(doc.node(), &attr, items.to_vec(), false) {
+            Ok((_, missing)) => Ok(missing),
+            Err(e) => Err(e.withfile(path)),
+        }
+    }
+
+    // The file defining `query`, the attribute as written there and the file's source
+    fn lookup(&self, query: &str) -> Result<(PathBuf, String, String), Error> {
+        let notfound = || Error::new(ErrorKind::NoAttr).withattr(query);
+        let (path, attr) = match self.find(query) {
+            Some(x) => x,
+            None => return Err(notfound()),
+        };
+        match self.source(&path) {
+            Some(f) => Ok((path, attr, f)),
+            None => Err(notfound()),
+        }
+    }
+
+    // Apply the operations to the files that define their attributes. New attributes
+    // go to the file that defines the longest part of their path, or the entry point.
+    // If any of them fails no file is changed.
+    pub fn apply(&mut self, ops: &[Operation]) -> Result<Vec<PathBuf>, Error> {
+        let mut modules = self.modules.clone();
+        let mut changed = self.changed.clone();
+        let mut out = vec![];
+        for op in ops {
+            let (i, attr) = target(&modules, op.attr());
+            let (path, doc) = &mut modules[i];
+            if let Err(e) = doc.apply(&[op.withattr(&attr)]) {
+                return Err(e.withfile(path));
+            }
+            changed.insert(i);
+            if !out.contains(&modules[i].0) {
+                out.push(modules[i].0.clone());
+            }
+        }
+        self.modules = modules;
+        self.changed = changed;
+        Ok(out)
+    }
+
+    pub fn write(&mut self, query: &str, val: &str) -> Result<PathBuf, Error> {
+        let op = Operation::Set {
+            attr: query.to_string(),
+            value: val.to_string(),
+        };
+        Ok(self.apply(&[op])?.remove(0))
+    }
+}
+
+fn target(modules: &[(PathBuf, Document)], query: &str) -> (usize, String) {
+    let path = AttrPath::parse(query);
+    for n in (1..=path.len()).rev() {
+        let prefix = AttrPath::new(path.segments()[..n].to_vec()).to_string();
+        for (i, (_, doc)) in modules.iter().enumerate() {
+            if let Some(attr) = resolve(doc.node(), &prefix) {
+                // Keep putting settings under `config` in modules that do
+                if attr == prefix {
+                    return (i, query.to_string());
+                }
+                return (i, format!("config.{}", query));
+            }
+        }
+    }
+    (0, query.to_string())
+}
+
+fn resolve(root: &SyntaxNode, query: &str) -> Option<String> {
+    let configbase = getcfgbase(root)?;
+    if findattr(&configbase, query).is_some() {
+        return Some(query.to_string());
+    }
+    // Modules declaring options put their settings under `config`
+    if findattr(&configbase, "config").is_some() {
+        let attr = format!("config.{}", query);
+        if findattr(&configbase, &attr).is_some() {
+            return Some(attr);
+        }
+    }
+    None
+}
+
+// Files listed in `imports`, relative to the module that imports them. Elements are
+// either a path or `import` applied to one. Paths that can only be known when
+// evaluating, like `<nixpkgs/...>` or `modulesPath + ...`, are left out.
+fn getimports(root: &SyntaxNode, file: &Path) -> Vec<PathBuf> {
+    let dir = match file.parent() {
+        Some(x) => x.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    let binding = match getcfgbase(root).and_then(|x| findattr(&x, "imports")) {
+        Some(x) => x,
+        None => return vec![],
+    };
+    listsegments(&binding)
+        .iter()
+        .flat_map(|(list, _, _)| list.children())
+        .filter_map(|x| importpath(&x))
+        .filter_map(|text| {
+            if text.starts_with('/') {
+                Some(PathBuf::from(text))
+            } else if let Some(rest) = text.strip_prefix("./") {
+                Some(dir.join(rest))
+            } else if text.starts_with("../") {
+                Some(dir.join(text))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// The path of an `imports` element like `./x.nix` or `import ./x.nix { ... }`,
+// leaving out the arguments
+fn importpath(elem: &SyntaxNode) -> Option<String> {
+    let mut node = elem.clone();
+    loop {
+        match node.kind() {
+            SyntaxKind::NODE_PAREN => node = node.first_child()?,
+            SyntaxKind::NODE_APPLY => {
+                let function = node.first_child()?;
+                if function.kind() == SyntaxKind::NODE_IDENT && function.text() == "import" {
+                    node = node.last_child()?;
+                } else {
+                    node = function;
+                }
+            }
+            SyntaxKind::NODE_LITERAL => {
+                let token = node.first_token()?;
+                return match token.kind() {
+                    SyntaxKind::TOKEN_PATH => Some(token.text().to_string()),
+                    _ => None,
+                };
+            }
+            _ => return None,
+        }
+    }
+}
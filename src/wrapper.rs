@@ -0,0 +1,191 @@
+use std::fmt;
+
+use rnix::{SyntaxKind, SyntaxNode};
+
+use crate::parse::getcfgbase;
+
+// A module system function wrapped around an option value, such as `lib.mkDefault`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Wrapper {
+    // `mkIf` with the source of its condition
+    If(String),
+    Merge,
+    Default,
+    Force,
+    // `mkOverride` with the source of its priority
+    Override(String),
+}
+
+impl Wrapper {
+    // Whether the wrapper only sets the priority of the value
+    pub fn ispriority(&self) -> bool {
+        matches!(self, Wrapper::Default | Wrapper::Force | Wrapper::Override(_))
+    }
+}
+
+impl fmt::Display for Wrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Wrapper::If(cond) => write!(f, "mkIf {}", cond),
+            Wrapper::Merge => write!(f, "mkMerge"),
+            Wrapper::Default => write!(f, "mkDefault"),
+            Wrapper::Force => write!(f, "mkForce"),
+            Wrapper::Override(priority) => write!(f, "mkOverride {}", priority),
+        }
+    }
+}
+
+// If `node` applies a wrapper, return it together with the wrapped expression
+pub fn getwrapper(node: &SyntaxNode) -> Option<(Wrapper, SyntaxNode)> {
+    if node.kind() != SyntaxKind::NODE_APPLY {
+        return None;
+    }
+    let (func, arg) = (node.first_child()?, node.last_child()?);
+    match funcname(&func).as_deref() {
+        Some("mkMerge") => return Some((Wrapper::Merge, arg)),
+        Some("mkDefault") => return Some((Wrapper::Default, arg)),
+        Some("mkForce") => return Some((Wrapper::Force, arg)),
+        _ => {}
+    }
+    // Wrappers taking two arguments are applied one argument at a time
+    if func.kind() != SyntaxKind::NODE_APPLY {
+        return None;
+    }
+    let (inner, first) = (func.first_child()?, func.last_child()?);
+    match funcname(&inner).as_deref() {
+        Some("mkIf") => Some((Wrapper::If(first.to_string()), arg)),
+        Some("mkOverride") => Some((Wrapper::Override(first.to_string()), arg)),
+        _ => None,
+    }
+}
+
+// Strip wrappers and parenthesis from a value, returning the inner value and the
+// wrappers from the outside in
+pub fn unwrap(node: &SyntaxNode) -> (SyntaxNode, Vec<Wrapper>) {
+    let mut node = node.clone();
+    let mut wrappers = vec![];
+    loop {
+        if node.kind() == SyntaxKind::NODE_PAREN {
+            match node.first_child() {
+                Some(x) => node = x,
+                None => break,
+            }
+        } else if let Some((w, inner)) = getwrapper(&node) {
+            // The list given to `mkMerge` is the value itself
+            let merge = w == Wrapper::Merge;
+            wrappers.push(w);
+            node = inner;
+            if merge {
+                break;
+            }
+        } else {
+            break;
+        }
+    }
+    (node, wrappers)
+}
+
+// The value inside any wrappers other than `mkMerge`, which is where a new value
+// goes when the wrappers should be kept
+pub(crate) fn innervalue(node: &SyntaxNode) -> SyntaxNode {
+    let mut node = node.clone();
+    loop {
+        let next = match getwrapper(&node) {
+            Some((Wrapper::Merge, _)) => None,
+            Some((_, inner)) => Some(inner),
+            None if node.kind() == SyntaxKind::NODE_PAREN => node.first_child(),
+            None => None,
+        };
+        match next {
+            Some(x) => node = x,
+            None => return node,
+        }
+    }
+}
+
+// Whether the file calls wrappers as `lib.mkDefault` or, under `with lib;`, as `mkDefault`
+pub(crate) fn libprefix(node: &SyntaxNode) -> &'static str {
+    let root = match node.ancestors().last() {
+        Some(x) => x,
+        None => node.clone(),
+    };
+    for n in root.descendants().filter(|x| getwrapper(x).is_some()) {
+        let mut func = n.first_child();
+        // `mkIf` and `mkOverride` are applied to two arguments
+        if let Some(x) = func.clone().filter(|x| x.kind() == SyntaxKind::NODE_APPLY) {
+            func = x.first_child();
+        }
+        match func.map(|x| x.kind()) {
+            Some(SyntaxKind::NODE_IDENT) => return "",
+            Some(SyntaxKind::NODE_SELECT) => return "lib.",
+            _ => {}
+        }
+    }
+    "lib."
+}
+
+// Whether `name` is bound by a function argument or `let` around `node`
+pub(crate) fn inscope(node: &SyntaxNode, name: &str) -> bool {
+    let isname = |x: Option<SyntaxNode>| x.is_some_and(|x| x.text() == name);
+    for ancestor in node.ancestors() {
+        let bound = match ancestor.kind() {
+            SyntaxKind::NODE_LAMBDA => match ancestor.first_child() {
+                Some(arg) if arg.kind() == SyntaxKind::NODE_PATTERN => arg
+                    .children()
+                    .filter(|x| {
+                        matches!(x.kind(), SyntaxKind::NODE_PAT_ENTRY | SyntaxKind::NODE_PAT_BIND)
+                    })
+                    .any(|x| isname(x.children().find(|y| y.kind() == SyntaxKind::NODE_IDENT))),
+                arg => isname(arg),
+            },
+            SyntaxKind::NODE_LET_IN => ancestor.children().any(|x| match x.kind() {
+                SyntaxKind::NODE_KEY_VALUE => isname(x.first_child()),
+                SyntaxKind::NODE_INHERIT => x.children().any(|y| isname(Some(y))),
+                _ => false,
+            }),
+            _ => false,
+        };
+        if bound {
+            return true;
+        }
+    }
+    false
+}
+
+// Wrappers applied to the enclosing sets of `node`, from the outside in
+pub fn outerwrappers(node: &SyntaxNode) -> Vec<Wrapper> {
+    let mut out = vec![];
+    let mut prev = node.clone();
+    for ancestor in node.ancestors().skip(1) {
+        if let Some((w, inner)) = getwrapper(&ancestor) {
+            if inner == prev {
+                out.push(w);
+            }
+        }
+        prev = ancestor;
+    }
+    out.reverse();
+    out
+}
+
+// The attribute sets a value contributes, looking through wrappers and the
+// elements of `mkMerge`
+pub fn attrsets(node: &SyntaxNode) -> Vec<SyntaxNode> {
+    let (inner, wrappers) = unwrap(node);
+    if wrappers.last() == Some(&Wrapper::Merge) && inner.kind() == SyntaxKind::NODE_LIST {
+        return inner.children().flat_map(|x| attrsets(&x)).collect();
+    }
+    match getcfgbase(&inner) {
+        Some(x) => vec![x],
+        None => vec![],
+    }
+}
+
+fn funcname(node: &SyntaxNode) -> Option<String> {
+    match node.kind() {
+        SyntaxKind::NODE_IDENT => Some(node.text().to_string()),
+        // `lib.mkIf` and `lib.modules.mkIf` are the same function
+        SyntaxKind::NODE_SELECT => node.last_child().map(|x| x.text().to_string()),
+        _ => None,
+    }
+}
@@ -1,154 +1,376 @@
-use std::{collections::HashMap, hash::Hash, thread::panicking};
+use std::collections::HashMap;
 
 use crate::{
-    parse::{findattr, getcfgbase, getkey},
-    read::findvalue,
+    comment::{attached, render},
+    errors::{attrerror, valueerror, Error, ErrorKind},
+    indent::{childindent, indentunit, lineindent, reindent},
+    parse::{findattr, getcfgbase, parsechecked, parsequery, resolvepath, AttrPath, ParseError, Resolution},
+    read::{elemkey, findvalue, listsegments, missingarr_node},
+    value::{argument, NixValue},
+    wrapper::{attrsets, getwrapper, innervalue, inscope, libprefix, Wrapper},
 };
-use failure::Fail;
 use rnix::{self, SyntaxKind, SyntaxNode};
+use rowan::{GreenNode, GreenToken, NodeOrToken};
 
-#[derive(Fail, Debug)]
-pub enum WriteError {
-    #[fail(display = "Write Error: Error while parsing.")]
-    ParseError,
-    #[fail(display = "Write Error: No attributes.")]
-    NoAttr,
-    #[fail(display = "Write Error: Error with array.")]
-    ArrayError,
-    #[fail(display = "Write Error: Writing value to attribute set.")]
-    WriteValueToSet,
-}
-
-pub fn write(f: &str, query: &str, val: &str) -> Result<String, WriteError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+pub fn write(f: &str, query: &str, val: &str) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(write_node(&root, query, val)?.to_string())
+}
+
+pub fn write_typed(f: &str, query: &str, val: &NixValue) -> Result<String, Error> {
+    let val = val.to_nix().map_err(|e| e.withattr(query))?;
+    write(f, query, &val)
+}
+
+pub(crate) fn write_node(
+    root: &SyntaxNode,
+    query: &str,
+    val: &str,
+) -> Result<SyntaxNode, Error> {
+    checkvalue(val, query)?;
+    checkquery(query)?;
+    let configbase = match getcfgbase(root) {
         Some(x) => x,
         None => {
-            return Err(WriteError::ParseError);
+            return Err(ParseError::default().into());
         }
     };
     if val.trim_start().starts_with('{') && val.trim_end().ends_with('}'){
         if let Some(x) = getcfgbase(&rnix::parse(val).node()) {
             if x.kind() == SyntaxKind::NODE_ATTR_SET {
-                return addattrval(f, &configbase, query, &x);
+                return addattrval(&configbase, query, &x);
             }
         }
     }
-    
+
     let outnode = match findattr(&configbase, query) {
         Some(x) => {
             if let Some(n) = x.children().last() {
                 if n.kind() == SyntaxKind::NODE_ATTR_SET {
-                    return Err(WriteError::WriteValueToSet);
+                    return Err(attrerror(ErrorKind::WriteValueToSet, query, &root.to_string()));
                 }
             }
-            modvalue(&x, val).unwrap()
+            match modvalue(&x, val) {
+                Some(x) => x,
+                None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+            }
         }
         None => {
-            let mut y = query.split('.').collect::<Vec<_>>();
-            y.pop();
-            let x = findattrset(&configbase, &y.join("."), 0);
-            match x {
-                Some((base, v, spaces)) => addvalue(
+            // Adding `a.b` next to `a = import ./x.nix;` would define `a` twice
+            if let Resolution::NotAttrSet { .. } = resolvepath(&configbase, query) {
+                return Err(attrerror(ErrorKind::NotAttrSet, query, &root.to_string()));
+            }
+            let qkey = AttrPath::parse(query);
+            let x = findattrset(&configbase, &qkey.parent().to_string());
+            let out = match x {
+                Some((base, v)) => addvalue(
                     &base,
-                    &format!("{}{}", " ".repeat(spaces), &query[v.len() + 1..]),
+                    &qkey.skip(AttrPath::parse(&v).len()).to_string(),
                     val,
                 ),
                 None => addvalue(&configbase, query, val),
+            };
+            match out {
+                Some(x) => x,
+                None => return Err(unwritable(query)),
             }
         }
     };
-    Ok(outnode.to_string())
+    Ok(outnode)
 }
 
-fn addvalue(configbase: &SyntaxNode, query: &str, val: &str) -> SyntaxNode {
-    let mut index = configbase.green().children().len() - 2;
-    // To find a better index for insertion, first find a matching node, then find the next newline token, after that, insert
-    match matchval(configbase, query, query.split('.').count()) {
-        Some(x) => {
-            let i = configbase
-                .green()
-                .children()
-                .position(|y| match y.into_node() {
-                    Some(y) => *y == x.green().to_owned(),
-                    None => false,
+pub fn write_inner(f: &str, query: &str, val: &str) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(write_inner_node(&root, query, val)?.to_string())
+}
+
+// Like `write_node`, but a value wrapped in `mkForce`, `mkOverride`, `mkIf`, ...
+// keeps its wrappers and only the value inside them is replaced
+pub(crate) fn write_inner_node(
+    root: &SyntaxNode,
+    query: &str,
+    val: &str,
+) -> Result<SyntaxNode, Error> {
+    checkvalue(val, query)?;
+    let value = match findwritable(root, query)? {
+        Some(x) => x,
+        None => return write_node(root, query, val),
+    };
+    let inner = innervalue(&value);
+    if inner == value {
+        return write_node(root, query, val);
+    }
+    let val = reindent(val, &lineindent(&inner), &indentunit(&inner));
+    // Without parenthesis around it the new value is an argument of the wrapper
+    let val = if inner.parent().map(|x| x.kind()) == Some(SyntaxKind::NODE_APPLY) {
+        argument(&val)
+    } else {
+        val
+    };
+    replacevalue(&inner, &val).map_err(|e| attrerror(e, query, &root.to_string()))
+}
+
+pub fn addwrapper(f: &str, query: &str, wrapper: &Wrapper) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(addwrapper_node(&root, query, wrapper)?.to_string())
+}
+
+// Wrap the value of a binding, replacing any priority it already has if `wrapper` is one
+pub(crate) fn addwrapper_node(
+    root: &SyntaxNode,
+    query: &str,
+    wrapper: &Wrapper,
+) -> Result<SyntaxNode, Error> {
+    let value = match findwritable(root, query)? {
+        Some(x) => x,
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    let mut inner = value.clone();
+    if wrapper.ispriority() {
+        while let Some((w, x)) = getwrapper(&inner) {
+            if !w.ispriority() {
+                break;
+            }
+            inner = x;
+        }
+    }
+    let call = match wrapper {
+        Wrapper::If(cond) => format!("mkIf {}", argument(cond)),
+        Wrapper::Override(priority) => format!("mkOverride {}", argument(priority)),
+        w => w.to_string(),
+    };
+    let prefix = libprefix(root);
+    let text = format!("{}{} {}", prefix, call, argument(&inner.to_string()));
+    let out = replacevalue(&value, &text).map_err(|e| attrerror(e, query, &root.to_string()))?;
+    if !prefix.is_empty() && !inscope(&value, "lib") {
+        return Ok(addlib(&out));
+    }
+    Ok(out)
+}
+
+// Add `lib` to the arguments of the function the file is, or make the file a function
+// taking it
+fn addlib(root: &SyntaxNode) -> SyntaxNode {
+    let top = match root.first_child() {
+        Some(x) => x,
+        None => return root.clone(),
+    };
+    let start = usize::from(top.text_range().start());
+    let (index, text) = match top.first_child().filter(|_| top.kind() == SyntaxKind::NODE_LAMBDA) {
+        Some(arg) if arg.kind() == SyntaxKind::NODE_PATTERN => {
+            let entries = arg
+                .children_with_tokens()
+                .filter(|x| {
+                    matches!(x.kind(), SyntaxKind::NODE_PAT_ENTRY | SyntaxKind::TOKEN_ELLIPSIS)
                 })
-                .unwrap();
-            let configafter = &configbase.green().children().collect::<Vec<_>>()[i..];
-            for child in configafter {
-                match child.as_token() {
-                    Some(x) => {
-                        if x.text().contains('\n') {
-                            let cas = configafter.to_vec();
-                            index = i + cas
-                                .iter()
-                                .position(|y| match y.as_token() {
-                                    Some(t) => t == x,
-                                    None => false,
-                                })
-                                .unwrap();
-                            break;
-                        }
-                    }
-                    None => {}
+                .collect::<Vec<_>>();
+            // Written after `config` as in the usual `{ config, lib, pkgs, ... }`
+            let config = entries.iter().find(|x| match x.as_node() {
+                Some(x) => x.first_child().is_some_and(|y| y.text() == "config"),
+                None => false,
+            });
+            match (config, entries.first(), arg.last_token()) {
+                (Some(x), _, _) => (usize::from(x.text_range().end()), ", lib"),
+                (None, Some(x), _) => (usize::from(x.text_range().start()), "lib, "),
+                (None, None, Some(x)) if x.kind() == SyntaxKind::TOKEN_CURLY_B_CLOSE => {
+                    (usize::from(x.text_range().start()), "lib ")
                 }
+                _ => (start, "{ lib, ... }:\n\n"),
             }
         }
-        None => {}
+        Some(arg) if arg.kind() == SyntaxKind::NODE_IDENT => {
+            (usize::from(arg.text_range().start()), "{ lib, ... }@")
+        }
+        _ => (start, "{ lib, ... }:\n\n"),
+    };
+    let mut out = root.to_string();
+    out.insert_str(index, text);
+    rnix::parse(&out).node()
+}
+
+pub fn rmwrapper(f: &str, query: &str) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(rmwrapper_node(&root, query)?.to_string())
+}
+
+// Remove the `mkDefault`, `mkForce` or `mkOverride` wrappers around the value of a binding
+pub(crate) fn rmwrapper_node(root: &SyntaxNode, query: &str) -> Result<SyntaxNode, Error> {
+    let value = match findwritable(root, query)? {
+        Some(x) => x,
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    let mut inner = value.clone();
+    while let Some((w, x)) = getwrapper(&inner) {
+        if !w.ispriority() {
+            break;
+        }
+        inner = x;
+    }
+    if inner == value {
+        return Ok(root.clone());
+    }
+    if inner.kind() == SyntaxKind::NODE_PAREN {
+        if let Some(x) = inner.first_child() {
+            inner = x;
+        }
+    }
+    replacevalue(&value, &inner.to_string()).map_err(|e| attrerror(e, query, &root.to_string()))
+}
+
+// Attributes are checked before they are added, so a path like `a..b` fails
+fn checkquery(query: &str) -> Result<(), Error> {
+    match parsequery(query) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::new(ErrorKind::InvalidAttr(e)).withattr(query)),
     }
-    let input = rnix::parse(format!("\n  {} = {};", &query, &val).as_str())
-        .node()
+}
+
+fn unwritable(query: &str) -> Error {
+    let reason = "it does not make a valid binding".to_string();
+    Error::new(ErrorKind::InvalidAttr(reason)).withattr(query)
+}
+
+// Values given by the caller are checked before they are spliced into the file
+fn checkvalue(val: &str, query: &str) -> Result<(), Error> {
+    match parsechecked(val) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(valueerror(e, query)),
+    }
+}
+
+// The value of a binding that exists as a single node in the file
+fn findwritable(root: &SyntaxNode, query: &str) -> Result<Option<SyntaxNode>, Error> {
+    let configbase = match getcfgbase(root) {
+        Some(x) => x,
+        None => return Err(ParseError::default().into()),
+    };
+    match findattr(&configbase, query).and_then(|x| findvalue(&x)) {
+        Some(x) if x.ancestors().last().as_ref() == Some(root) => Ok(Some(x)),
+        _ => Ok(None),
+    }
+}
+
+fn replacevalue(node: &SyntaxNode, val: &str) -> Result<SyntaxNode, ErrorKind> {
+    let new = match rnix::parse(val).node().first_child() {
+        Some(x) => x,
+        None => return Err(ErrorKind::Parse(ParseError::default())),
+    };
+    let parent = match node.parent() {
+        Some(x) => x,
+        None => return Err(ErrorKind::NoAttr),
+    };
+    let index = match parent.children_with_tokens().position(|x| x.as_node() == Some(node)) {
+        Some(x) => x,
+        None => return Err(ErrorKind::NoAttr),
+    };
+    let replaced = parent
+        .green()
+        .replace_child(index, NodeOrToken::Node(new.green().to_owned()));
+    Ok(SyntaxNode::new_root(parent.replace_with(replaced)))
+}
+
+// Parse `text` between the given delimiters and return the elements in between,
+// so they can be spliced into an existing node without reparsing the file
+fn fragment(open: &str, text: &str, close: &str) -> Vec<NodeOrToken<GreenNode, GreenToken>> {
+    let ast = rnix::parse(&format!("{}{}{}", open, text, close));
+    let wrapper = match ast.node().first_child() {
+        Some(x) => x,
+        None => return vec![],
+    };
+    let children = wrapper
         .green()
-        .to_owned();
+        .children()
+        .map(|c| match c {
+            NodeOrToken::Node(n) => NodeOrToken::Node(n.to_owned()),
+            NodeOrToken::Token(t) => NodeOrToken::Token(t.to_owned()),
+        })
+        .collect::<Vec<_>>();
+    if children.len() < 2 {
+        return vec![];
+    }
+    children[1..children.len() - 1].to_vec()
+}
+
+// To find a better index for insertion, first find a matching node, then find the next newline token
+fn insertindex(configbase: &SyntaxNode, query: &str) -> Option<usize> {
+    let x = matchval(configbase, query, AttrPath::parse(query).len())?;
+    let i = configbase
+        .green()
+        .children()
+        .position(|y| match y.into_node() {
+            Some(y) => *y == x.green().to_owned(),
+            None => false,
+        })?;
+    let j = configbase
+        .green()
+        .children()
+        .skip(i)
+        .position(|y| match y.as_token() {
+            Some(t) => t.text().contains('\n'),
+            None => false,
+        })?;
+    Some(i + j)
+}
+
+// Add a binding for `query` to the set, or None if it would not be valid Nix
+fn addvalue(configbase: &SyntaxNode, query: &str, val: &str) -> Option<SyntaxNode> {
+    let mut index = match insertindex(configbase, query) {
+        Some(x) => x,
+        None => configbase.green().children().len() - 2,
+    };
+    let unit = indentunit(configbase);
+    let indent = childindent(configbase, &unit);
+    // Segments that are not identifiers are written quoted
+    let key = AttrPath::parse(query).to_string();
+    let input = fragment(
+        "{",
+        &format!("\n{}{} = {};", indent, key, reindent(val, &indent, &unit)),
+        "}",
+    );
     if index == 0 {
         index += 1;
     };
-    let new = configbase
-        .green()
-        .insert_child(index, rnix::NodeOrToken::Node(input));
-    let replace = configbase.replace_with(new);
-    rnix::parse(&replace.to_string()).node()
+    let new = configbase.green().splice_children(index..index, input);
+    let out = SyntaxNode::new_root(configbase.replace_with(new));
+    match parsechecked(&out.to_string()) {
+        Ok(_) => Some(out),
+        Err(_) => None,
+    }
 }
 
-// Currently indentation is badly done by inserting spaces, it should check the spaces of the previous attr instead
-fn findattrset(
-    configbase: &SyntaxNode,
-    name: &str,
-    spaces: usize,
-) -> Option<(SyntaxNode, String, usize)> {
+// Find the set to add `name` to, looking inside `mkIf` and `mkMerge`. Returns the
+// deepest existing set on the path together with the part of `name` it is at.
+fn findattrset(configbase: &SyntaxNode, name: &str) -> Option<(SyntaxNode, String)> {
     for child in configbase.children() {
         if child.kind() == SyntaxKind::NODE_KEY_VALUE {
             // Now we have to read all the indent values from the key
             for subchild in child.children() {
                 if subchild.kind() == SyntaxKind::NODE_KEY {
                     // We have a key, now we need to check if it's the one we're looking for
-                    let key = getkey(&subchild);
-                    let qkey = name
-                        .split('.')
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
+                    let key = AttrPath::from_key(&subchild);
+                    let qkey = AttrPath::parse(name);
+                    let sets = match child.last_child() {
+                        Some(x) if x != subchild => attrsets(&x)
+                            .into_iter()
+                            .filter(|x| x.kind() == SyntaxKind::NODE_ATTR_SET)
+                            .collect::<Vec<_>>(),
+                        _ => vec![],
+                    };
                     if qkey == key {
                         // We have key, now lets find the attrset
-                        for possibleset in child.children() {
-                            if possibleset.kind() == SyntaxKind::NODE_ATTR_SET {
-                                return Some((possibleset, name.to_string(), spaces + 2));
-                            }
-                        }
-                        return None;
-                    } else if qkey.len() > key.len() {
+                        return sets.first().map(|x| (x.clone(), name.to_string()));
+                    } else if qkey.len() > key.len() && qkey.starts_with(&key) {
                         // We have a subkey, so we need to recurse
-                        if key == qkey[0..key.len()] {
-                            // We have a subkey, so we need to recurse
-                            let subkey = &qkey[key.len()..].join(".").to_string();
-                            let newbase = getcfgbase(&child).unwrap();
-                            let subattr = findattrset(&newbase, subkey, spaces + 2);
-                            match subattr {
-                                Some((node, _, spaces)) => {
-                                    return Some((node, name.to_string(), spaces));
-                                }
-                                None => {}
+                        let subkey = qkey.skip(key.len()).to_string();
+                        for newbase in &sets {
+                            if let Some((node, v)) = findattrset(newbase, &subkey) {
+                                return Some((node, key.join(&AttrPath::parse(&v)).to_string()));
                             }
                         }
+                        // The rest of the path does not exist yet, so it goes in this set
+                        if let Some(x) = sets.first() {
+                            return Some((x.clone(), key.to_string()));
+                        }
                     }
                 }
             }
@@ -158,17 +380,14 @@ fn findattrset(
 }
 
 fn matchval(configbase: &SyntaxNode, query: &str, acc: usize) -> Option<SyntaxNode> {
-    let qvec = &query
-        .split('.')
-        .map(|s| s.to_string())
-        .collect::<Vec<String>>();
-    let q = &qvec[..acc];
+    let qvec = AttrPath::parse(query);
+    let q = &qvec.segments()[..acc];
     for child in configbase.children() {
         if child.kind() == SyntaxKind::NODE_KEY_VALUE {
             for subchild in child.children() {
                 if subchild.kind() == SyntaxKind::NODE_KEY {
-                    let key = getkey(&subchild);
-                    if key.len() >= q.len() && &key[..q.len()] == q {
+                    let key = AttrPath::from_key(&subchild);
+                    if key.segments().starts_with(q) {
                         return Some(child);
                     }
                 }
@@ -187,25 +406,20 @@ fn modvalue(node: &SyntaxNode, val: &str) -> Option<SyntaxNode> {
     for child in node.children() {
         if child.kind() != SyntaxKind::NODE_KEY {
             let c = &child;
-            let input = val.to_string();
-            let rep = &rnix::parse(&input)
-                .node()
-                .children()
-                .collect::<Vec<SyntaxNode>>()[0];
+            let input = reindent(val, &lineindent(node), &indentunit(node));
+            let rep = &rnix::parse(&input).node().first_child()?;
             let index = node
                 .green()
                 .children()
                 .position(|y| match y.into_node() {
                     Some(y) => *y == c.green().to_owned(),
                     None => false,
-                })
-                .unwrap();
+                })?;
             let replaced = node
                 .green()
                 .replace_child(index, rnix::NodeOrToken::Node(rep.green().to_owned()));
             let out = node.replace_with(replaced);
-            let rnode = rnix::parse(&out.to_string()).node();
-            return Some(rnode);
+            return Some(SyntaxNode::new_root(out));
         }
     }
     None
@@ -213,28 +427,28 @@ fn modvalue(node: &SyntaxNode, val: &str) -> Option<SyntaxNode> {
 
 // Add an attribute to the config
 fn addattrval(
-    f: &str,
     configbase: &SyntaxNode,
     query: &str,
     val: &SyntaxNode,
-) -> Result<String, WriteError> {
+) -> Result<SyntaxNode, Error> {
     let mut attrmap = HashMap::new();
     buildattrvec(val, vec![], &mut attrmap);
-    let mut file = f.to_string();
 
     if attrmap.iter().any(|(key, _)| findattr(configbase, &format!("{}.{}", query, key)).is_some()) {
+        let mut root = match configbase.ancestors().last() {
+            Some(x) => x,
+            None => return Err(ParseError::default().into()),
+        };
         for (key, val) in attrmap {
-            match write(&file, &format!("{}.{}", query, key), &val) {
-                Ok(x) => {
-                    file = x
-                },
-                Err(e) => return Err(e),
-            }
+            root = write_node(&root, &format!("{}.{}", query, key), &val)?;
         }
-    } else if let Some(c) = getcfgbase(&rnix::parse(&file).node()) {
-        file = addvalue(&c, query, &val.to_string()).to_string();
-    }    
-    Ok(file)
+        Ok(root)
+    } else {
+        match addvalue(configbase, query, &val.to_string()) {
+            Some(x) => Ok(x),
+            None => Err(unwritable(query)),
+        }
+    }
 }
 
 fn buildattrvec(val: &SyntaxNode, prefix: Vec<String>, map: &mut HashMap<String, String>) {
@@ -244,9 +458,9 @@ fn buildattrvec(val: &SyntaxNode, prefix: Vec<String>, map: &mut HashMap<String,
                 if subchild.kind() == SyntaxKind::NODE_ATTR_SET {
                     for c in child.children() {
                         if c.kind() == SyntaxKind::NODE_KEY {
-                            let key = getkey(&c);
+                            let key = AttrPath::from_key(&c);
                             let mut newprefix = prefix.clone();
-                            newprefix.append(&mut key.clone());
+                            newprefix.extend(key.segments().iter().cloned());
                             buildattrvec(&subchild, newprefix, map);
                             break;
                         }
@@ -254,10 +468,10 @@ fn buildattrvec(val: &SyntaxNode, prefix: Vec<String>, map: &mut HashMap<String,
                 } else {
                     for c in child.children() {
                         if c.kind() == SyntaxKind::NODE_KEY {
-                            let key = getkey(&c);
+                            let key = AttrPath::from_key(&c);
                             let mut newprefix = prefix.clone();
-                            newprefix.append(&mut key.clone());
-                            map.insert(newprefix.join("."), subchild.to_string());
+                            newprefix.extend(key.segments().iter().cloned());
+                            map.insert(AttrPath::new(newprefix).to_string(), subchild.to_string());
                         }
                     }
                 }
@@ -266,162 +480,436 @@ fn buildattrvec(val: &SyntaxNode, prefix: Vec<String>, map: &mut HashMap<String,
     }
 }
 
-pub fn addtoarr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+// Where `insertarr` adds elements to a list
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Position {
+    #[default]
+    End,
+    // Before the element at this index, or at the end if it is the length of the list
+    Index(usize),
+    // Before or after the element written exactly like this
+    Before(String),
+    After(String),
+    // In alphabetical order if the list is sorted, at the end otherwise
+    Sorted,
+}
+
+pub fn addtoarr(f: &str, query: &str, items: Vec<String>) -> Result<String, Error> {
+    insertarr(f, query, items, &Position::End)
+}
+
+pub(crate) fn addtoarr_node(
+    root: &SyntaxNode,
+    query: &str,
+    items: Vec<String>,
+) -> Result<SyntaxNode, Error> {
+    insertarr_node(root, query, items, &Position::End)
+}
+
+pub fn insertarr(
+    f: &str,
+    query: &str,
+    items: Vec<String>,
+    position: &Position,
+) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(insertarr_node(&root, query, items, position)?.to_string())
+}
+
+pub(crate) fn insertarr_node(
+    root: &SyntaxNode,
+    query: &str,
+    items: Vec<String>,
+    position: &Position,
+) -> Result<SyntaxNode, Error> {
+    for item in &items {
+        checkvalue(item, query)?;
+    }
+    checkquery(query)?;
+    let configbase = match getcfgbase(root) {
         Some(x) => x,
-        None => return Err(WriteError::ParseError),
+        None => return Err(ParseError::default().into()),
     };
     let outnode = match findattr(&configbase, query) {
-        Some(x) => match addtoarr_aux(&x, items) {
-            Some(x) => x,
-            None => return Err(WriteError::ArrayError),
+        Some(x) => match insertarr_aux(&x, items, position) {
+            Ok(x) => x,
+            Err(e) => return Err(attrerror(e, query, &root.to_string())),
         },
         // If no arrtibute is found, create a new one
         None => {
-            let newval = addvalue(&configbase, query, "[\n  ]");
-            return addtoarr(&newval.to_string(), query, items);
+            if let Resolution::NotAttrSet { .. } = resolvepath(&configbase, query) {
+                return Err(attrerror(ErrorKind::NotAttrSet, query, &root.to_string()));
+            }
+            let newval = match addvalue(&configbase, query, "[\n]") {
+                Some(x) => x,
+                None => return Err(unwritable(query)),
+            };
+            // The new list is not in the file, so errors about it point nowhere
+            return insertarr_node(&newval, query, items, position)
+                .map_err(|e| attrerror(e.kind, query, &root.to_string()));
         }
     };
-    Ok(outnode.to_string())
+    Ok(outnode)
 }
 
-fn addtoarr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
-    for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return addtoarr_aux(&child, items);
-        }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let mut green = child.green().to_owned();
-
-            for elem in items {
-                let mut i = 0;
-                for c in green.children() {
-                    if c.to_string() == "]" {
-                        if green.children().collect::<Vec<_>>()[i - 1]
-                            .as_token()
-                            .unwrap()
-                            .to_string()
-                            .contains('\n')
-                        {
-                            i -= 1;
-                        }
-                        green = green.insert_child(
-                            i,
-                            rnix::NodeOrToken::Node(
-                                rnix::parse(&format!("\n{}{}", " ".repeat(4), elem))
-                                    .node()
-                                    .green()
-                                    .to_owned(),
-                            ),
-                        );
-                        break;
-                    }
-                    i += 1;
-                }
-            }
+fn insertarr_aux(
+    node: &SyntaxNode,
+    items: Vec<String>,
+    position: &Position,
+) -> Result<SyntaxNode, ErrorKind> {
+    // Add to the first list that is always part of the value, not one under `lib.optionals`
+    let (child, withvals) = match listsegments(node).into_iter().find(|(_, condition, _)| condition.is_none()) {
+        Some((x, _, withvals)) => (x, withvals),
+        None => return Err(ErrorKind::Array),
+    };
+    let mut green = child.green().to_owned();
+    let unit = indentunit(&child);
+    let indent = childindent(&child, &unit);
+    let multiline = child.text().contains_char('\n');
 
-            let index = match node.green().children().position(|x| match x.into_node() {
-                Some(x) => *x == child.green().to_owned(),
-                None => false,
-            }) {
-                Some(x) => x,
-                None => return None,
-            };
+    // Anchors are matched like the elements `rmarr` removes, so `pkgs.git` finds `git`
+    let elems = child.children().map(|x| elemkey(&x.to_string(), &withvals)).collect::<Vec<_>>();
+    let find = |x: &str| match elems.iter().position(|e| *e == elemkey(x, &withvals)) {
+        Some(i) => Ok(i),
+        None => Err(ErrorKind::NoElement(vec![x.trim().to_string()])),
+    };
+    let start = match position {
+        Position::Index(i) if *i > elems.len() => return Err(ErrorKind::IndexOutOfRange(*i)),
+        Position::Index(i) => Some(*i),
+        Position::Before(x) => Some(find(x)?),
+        Position::After(x) => Some(find(x)? + 1),
+        Position::End | Position::Sorted => None,
+    };
 
-            let replace = node
-                .green()
-                .replace_child(index, rnix::NodeOrToken::Node(green));
-            let out = node.replace_with(replace);
-            let output = rnix::parse(&out.to_string()).node();
-            return Some(output);
+    for (n, item) in items.iter().enumerate() {
+        // Function applications like `callPackage ./foo.nix { }` are one element only in parenthesis
+        let elem = &argument(item);
+        let elems = green
+            .children()
+            .filter_map(|x| x.into_node().map(|x| x.to_string()))
+            .collect::<Vec<_>>();
+        let index = match (start, position) {
+            (Some(i), _) => i + n,
+            (None, Position::Sorted) if issorted(&elems) => elems
+                .iter()
+                .filter(|x| sortkey(x) <= sortkey(item))
+                .count(),
+            _ => elems.len(),
+        };
+        let text = if multiline {
+            format!("\n{}{}", indent, reindent(elem, &indent, &unit))
+        } else {
+            elem.to_string()
+        };
+        green = insertelem(&green, index, &text, multiline);
+    }
+
+    let out = child.replace_with(green);
+    Ok(SyntaxNode::new_root(out))
+}
+
+// Add the items that are not in the list yet, comparing them with `elemkey`, and
+// return the ones that were added
+pub fn ensurearr(
+    f: &str,
+    query: &str,
+    items: Vec<String>,
+    position: &Position,
+) -> Result<(String, Vec<String>), Error> {
+    let root = parsechecked(f)?;
+    let (out, added) = ensurearr_node(&root, query, items, position)?;
+    Ok((out.to_string(), added))
+}
+
+pub(crate) fn ensurearr_node(
+    root: &SyntaxNode,
+    query: &str,
+    items: Vec<String>,
+    position: &Position,
+) -> Result<(SyntaxNode, Vec<String>), Error> {
+    for item in &items {
+        checkvalue(item, query)?;
+    }
+    let added = missingarr_node(root, query, &items)?;
+    if added.is_empty() {
+        return Ok((root.clone(), added));
+    }
+    Ok((insertarr_node(root, query, added.clone(), position)?, added))
+}
+
+// Insert `text` into the list `green` before its element at `index`. In a list
+// written over several lines `text` starts with the line break and indentation of
+// the new element, and goes above any comment lines of the element it is put before.
+fn insertelem(green: &GreenNode, index: usize, text: &str, multiline: bool) -> GreenNode {
+    let children = green.children().map(|x| x.to_string()).collect::<Vec<_>>();
+    let nodes = green
+        .children()
+        .enumerate()
+        .filter(|(_, x)| x.as_node().is_some())
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let isbreak = |i: usize| children[i].trim().is_empty() && children[i].contains('\n');
+    let iscomment = |i: usize| children[i].starts_with('#') || children[i].starts_with("/*");
+    if multiline {
+        let mut i = match nodes.get(index) {
+            Some(x) => *x,
+            None => children.len() - 1,
+        };
+        // Without a line break before it, as for the bracket in `[ a]`, the new
+        // element goes right before it
+        if i > 0 && isbreak(i - 1) {
+            i -= 1;
+            while i >= 2 && iscomment(i - 1) && isbreak(i - 2) {
+                i -= 2;
+            }
         }
+        return green.splice_children(i..i, fragment("[", text, "]"));
+    }
+    match (nodes.get(index), index.checked_sub(1).and_then(|x| nodes.get(x))) {
+        (Some(i), _) => green.splice_children(*i..*i, fragment("[", &format!("{} ", text), "]")),
+        (None, Some(i)) => green.splice_children(i + 1..i + 1, fragment("[", &format!(" {}", text), "]")),
+        // An empty list, where the bracket may be right after the opening one
+        (None, None) if children.get(1).map(|x| x.as_str()) == Some("]") => {
+            green.splice_children(1..1, fragment("[", &format!(" {} ", text), "]"))
+        }
+        (None, None) => green.splice_children(1..1, fragment("[", &format!(" {}", text), "]")),
     }
-    None
 }
 
-pub fn rmarr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+fn issorted(elems: &[String]) -> bool {
+    elems.windows(2).all(|x| sortkey(&x[0]) <= sortkey(&x[1]))
+}
+
+fn sortkey(elem: &str) -> String {
+    elem.trim().to_lowercase()
+}
+
+pub fn rmarr(f: &str, query: &str, items: Vec<String>) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(rmarr_node(&root, query, items, false)?.0.to_string())
+}
+
+// Remove the elements matching `items` and return the items that are not in the
+// list. With `strict`, fail with `NoElement` instead without removing anything.
+pub fn removearr(
+    f: &str,
+    query: &str,
+    items: Vec<String>,
+    strict: bool,
+) -> Result<(String, Vec<String>), Error> {
+    let root = parsechecked(f)?;
+    let (out, missing) = rmarr_node(&root, query, items, strict)?;
+    Ok((out.to_string(), missing))
+}
+
+pub(crate) fn rmarr_node(
+    root: &SyntaxNode,
+    query: &str,
+    items: Vec<String>,
+    strict: bool,
+) -> Result<(SyntaxNode, Vec<String>), Error> {
+    for item in &items {
+        checkvalue(item, query)?;
+    }
+    let configbase = match getcfgbase(root) {
         Some(x) => x,
-        None => return Err(WriteError::ParseError),
+        None => return Err(ParseError::default().into()),
     };
     let outnode = match findattr(&configbase, query) {
-        Some(x) => match rmarr_aux(&x, items) {
-            Some(x) => x,
-            None => return Err(WriteError::ArrayError),
-        },
-        None => return Err(WriteError::NoAttr),
+        Some(x) => rmarr_aux(&x, items),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
     };
-    Ok(outnode.to_string())
+    match outnode {
+        Err(e) => Err(attrerror(e, query, &root.to_string())),
+        Ok((_, missing)) if strict && !missing.is_empty() => {
+            Err(attrerror(ErrorKind::NoElement(missing), query, &root.to_string()))
+        }
+        Ok((Some(x), missing)) => Ok((x, missing)),
+        Ok((None, missing)) => Ok((root.clone(), missing)),
+    }
 }
 
-fn rmarr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
-    for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return rmarr_aux(&child, items);
-        }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let green = child.green().to_owned();
-            let mut idx = vec![];
-            for elem in green.children() {
-                if elem.as_node() != None && items.contains(&elem.to_string()) {
-                    let index = match green.children().position(|x| match x.into_node() {
-                        Some(x) => {
-                            if let Some(y) = elem.as_node() {
-                                *x == y.to_owned().to_owned()
-                            } else {
-                                false
-                            }
-                        }
-                        None => false,
-                    }) {
-                        Some(x) => x,
-                        None => return None,
-                    };
-                    idx.push(index)
+// Remove the elements matching `items` by `elemkey`, with the line break before
+// them and a comment after them on the same line. Returns the items that are not
+// in the list, and no tree if nothing was removed.
+fn rmarr_aux(
+    node: &SyntaxNode,
+    items: Vec<String>,
+) -> Result<(Option<SyntaxNode>, Vec<String>), ErrorKind> {
+    let segments = listsegments(node);
+    if segments.is_empty() {
+        return Err(ErrorKind::Array);
+    }
+    let mut found = vec![false; items.len()];
+    let mut edits = vec![];
+    // Elements are removed from every list they are in, also those under `lib.optionals`
+    for (child, _, withvals) in segments {
+        let keys = items.iter().map(|x| elemkey(x, &withvals)).collect::<Vec<_>>();
+        let green = child.green().to_owned();
+        let children = green.children().collect::<Vec<_>>();
+        let mut ranges = vec![];
+        for (i, elem) in children.iter().enumerate() {
+            let elem = match elem.as_node() {
+                Some(x) => x.to_string(),
+                None => continue,
+            };
+            let key = elemkey(&elem, &withvals);
+            let mut matched = false;
+            for (j, k) in keys.iter().enumerate() {
+                if *k == key {
+                    found[j] = true;
+                    matched = true;
                 }
             }
-            let mut acc = 0;
-            let mut replace = green;
-
-            for i in idx {
-                replace = replace.remove_child(i - acc);
-                let mut v = vec![];
-                for c in replace.children() {
-                    v.push(c);
-                }
-                match v.get(i - acc - 1).unwrap().as_token() {
-                    Some(x) => {
-                        if x.to_string().contains('\n') {
-                            replace = replace.remove_child(i - acc - 1);
-                            acc += 1;
-                        }
-                    }
-                    None => {}
-                }
-                acc += 1;
+            if !matched {
+                continue;
             }
-            let out = child.replace_with(replace);
+            let iswhitespace = |x: usize| {
+                children
+                    .get(x)
+                    .is_some_and(|c| c.as_token().is_some() && c.to_string().trim().is_empty())
+            };
+            let start = if i > 0 && iswhitespace(i - 1) { i - 1 } else { i };
+            let trailing = children.get(i + 2).is_some_and(|c| c.to_string().starts_with('#'));
+            let end = if iswhitespace(i + 1) && !children[i + 1].to_string().contains('\n') && trailing {
+                i + 3
+            } else {
+                i + 1
+            };
+            ranges.push(start..end);
+        }
+        if ranges.is_empty() {
+            continue;
+        }
+        let mut replace = green.clone();
+        for range in ranges.into_iter().rev() {
+            replace = replace.splice_children(range, vec![]);
+        }
+        edits.push((child, replace));
+    }
+    let missing = items
+        .iter()
+        .zip(found)
+        .filter(|(_, f)| !f)
+        .map(|(x, _)| x.trim().to_string())
+        .collect::<Vec<_>>();
+    if edits.is_empty() {
+        return Ok((None, missing));
+    }
+    match replacelists(edits) {
+        Some(x) => Ok((Some(x), missing)),
+        None => Err(ErrorKind::Array),
+    }
+}
 
-            let output = rnix::parse(&out.to_string()).node();
-            return Some(output);
+// Replace the elements matching `old` by `elemkey` with `new`, keeping their place
+// in the list and the comments around them
+pub fn replacearr(f: &str, query: &str, old: &str, new: &str) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(replacearr_node(&root, query, old, new)?.to_string())
+}
+
+pub(crate) fn replacearr_node(
+    root: &SyntaxNode,
+    query: &str,
+    old: &str,
+    new: &str,
+) -> Result<SyntaxNode, Error> {
+    checkvalue(old, query)?;
+    checkvalue(new, query)?;
+    let configbase = match getcfgbase(root) {
+        Some(x) => x,
+        None => return Err(ParseError::default().into()),
+    };
+    let outnode = match findattr(&configbase, query) {
+        Some(x) => replacearr_aux(&x, old, new),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    outnode.map_err(|e| attrerror(e, query, &root.to_string()))
+}
+
+fn replacearr_aux(
+    node: &SyntaxNode,
+    old: &str,
+    new: &str,
+) -> Result<SyntaxNode, ErrorKind> {
+    let segments = listsegments(node);
+    if segments.is_empty() {
+        return Err(ErrorKind::Array);
+    }
+    let mut edits = vec![];
+    for (child, _, withvals) in segments {
+        let key = elemkey(old, &withvals);
+        let mut green = child.green().to_owned();
+        let unit = indentunit(&child);
+        let mut found = false;
+        for elem in child.children() {
+            if elemkey(&elem.to_string(), &withvals) != key {
+                continue;
+            }
+            let index = match child.children_with_tokens().position(|x| x.as_node() == Some(&elem)) {
+                Some(x) => x,
+                None => continue,
+            };
+            // Function applications would be split into several elements without parenthesis
+            let text = reindent(&argument(new), &lineindent(&elem), &unit);
+            let value = match fragment("[", &text, "]").into_iter().find(|x| x.as_node().is_some()) {
+                Some(x) => x,
+                None => return Err(ErrorKind::Array),
+            };
+            green = green.replace_child(index, value);
+            found = true;
+        }
+        if found {
+            edits.push((child, green));
         }
     }
-    None
+    match replacelists(edits) {
+        Some(x) => Ok(x),
+        None => Err(ErrorKind::NoElement(vec![old.trim().to_string()])),
+    }
+}
+
+// Put new versions of lists into the tree they are from. Each list is found again
+// by its position, as changing one list leaves the nodes of the others stale.
+fn replacelists(edits: Vec<(SyntaxNode, GreenNode)>) -> Option<SyntaxNode> {
+    let mut root = edits.first()?.0.ancestors().last()?;
+    let paths = edits.iter().map(|(x, _)| treepath(x)).collect::<Vec<_>>();
+    for (path, (_, green)) in paths.into_iter().zip(edits) {
+        let mut node = root.clone();
+        for i in path {
+            node = node.children().nth(i)?;
+        }
+        root = SyntaxNode::new_root(node.replace_with(green));
+    }
+    Some(root)
 }
 
-pub fn deref(f: &str, query: &str) -> Result<String, WriteError> {
-    let ast = rnix::parse(f);
-    let configbase = match getcfgbase(&ast.node()) {
+// The indices of the nodes leading from the root to `node`
+fn treepath(node: &SyntaxNode) -> Vec<usize> {
+    let mut path = node
+        .ancestors()
+        .filter_map(|x| x.parent()?.children().position(|c| c == x))
+        .collect::<Vec<_>>();
+    path.reverse();
+    path
+}
+
+pub fn deref(f: &str, query: &str) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(deref_node(&root, query)?.to_string())
+}
+
+pub(crate) fn deref_node(root: &SyntaxNode, query: &str) -> Result<SyntaxNode, Error> {
+    let configbase = match getcfgbase(root) {
         Some(x) => x,
-        None => return Err(WriteError::ParseError),
+        None => return Err(ParseError::default().into()),
     };
     let outnode = match deref_aux(&configbase, query) {
         Some(x) => x,
-        None => return Err(WriteError::NoAttr),
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
     };
-    Ok(outnode.to_string())
+    Ok(outnode)
 }
 
 fn deref_aux(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
@@ -431,42 +919,32 @@ fn deref_aux(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
             for subchild in child.children() {
                 if subchild.kind() == SyntaxKind::NODE_KEY {
                     // We have a key, now we need to check if it's the one we're looking for
-                    let key = getkey(&subchild);
-                    let qkey = name
-                        .split('.')
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
+                    let key = AttrPath::from_key(&subchild);
+                    let qkey = AttrPath::parse(name);
                     if qkey == key {
-                        let index =
-                            match configbase
-                                .green()
-                                .children()
-                                .position(|x| match x.into_node() {
-                                    Some(x) => *x == child.green().to_owned(),
-                                    None => false,
-                                }) {
-                                Some(x) => x,
-                                None => return None,
-                            };
-                        let mut del = configbase.green().remove_child(index);
+                        // Comments documenting the binding go with it
+                        let (first, _, last) = attached(&child)?;
+                        let mut del = configbase.green().splice_children(first..last + 1, vec![]);
 
                         // Remove leading newline if it still exists
-                        if del.children().collect::<Vec<_>>()[index]
+                        if del.children().collect::<Vec<_>>()[first]
                             .to_string()
                             .contains('\n')
                         {
-                            del = del.remove_child(index);
+                            del = del.remove_child(first);
                         }
                         let out = configbase.replace_with(del);
-                        return Some(rnix::parse(&out.to_string()).node());
-                    } else if qkey.len() > key.len() {
-                        // We have a subkey, so we need to recurse
-                        if key == qkey[0..key.len()] {
-                            // We have a subkey, so we need to recurse
-                            let subkey = &qkey[key.len()..].join(".").to_string();
-                            let newbase = getcfgbase(&child).unwrap();
-                            let subattr = deref_aux(&newbase, subkey);
-                            if let Some(s) = subattr {
+                        return Some(SyntaxNode::new_root(out));
+                    } else if qkey.len() > key.len() && qkey.starts_with(&key) {
+                        // We have a subkey, so we need to recurse into every set of
+                        // the value, also those under `mkIf` and `mkMerge`
+                        let subkey = qkey.skip(key.len()).to_string();
+                        let sets = match child.last_child() {
+                            Some(x) if x != subchild => attrsets(&x),
+                            _ => continue,
+                        };
+                        for newbase in sets.iter().filter(|x| x.kind() == SyntaxKind::NODE_ATTR_SET) {
+                            if let Some(s) = deref_aux(newbase, &subkey) {
                                 return Some(s);
                             }
                         }
@@ -477,3 +955,54 @@ fn deref_aux(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
     }
     None
 }
+
+pub fn setcomment(f: &str, query: &str, comment: Option<&str>) -> Result<String, Error> {
+    let root = parsechecked(f)?;
+    Ok(setcomment_node(&root, query, comment)?.to_string())
+}
+
+// Replace the comment lines above a binding, or remove them if `comment` is None
+pub(crate) fn setcomment_node(
+    root: &SyntaxNode,
+    query: &str,
+    comment: Option<&str>,
+) -> Result<SyntaxNode, Error> {
+    let configbase = match getcfgbase(root) {
+        Some(x) => x,
+        None => return Err(ParseError::default().into()),
+    };
+    // Values merged from several bindings have no single place for a comment
+    let node = match findattr(&configbase, query) {
+        Some(x) if x.ancestors().last().as_ref() == Some(root) => x,
+        _ => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    let (first, index, _) = match attached(&node) {
+        Some(x) => x,
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    let parent = match node.parent() {
+        Some(x) => x,
+        None => return Err(attrerror(ErrorKind::NoAttr, query, &root.to_string())),
+    };
+    let before = match index.checked_sub(1) {
+        Some(i) => parent.children_with_tokens().nth(i).and_then(|x| x.into_token()),
+        None => None,
+    };
+    let mut first = first;
+    let input = match (comment, before) {
+        (Some(c), Some(ws)) if ws.kind() == SyntaxKind::TOKEN_WHITESPACE && ws.text().contains('\n') => {
+            let indent = ws.text().rsplit('\n').next().unwrap_or_default();
+            fragment("{", &format!("{}\n{}", render(c, indent), indent), "}")
+        }
+        // The binding does not start its own line, so the comment needs one
+        (Some(c), before) => {
+            if before.map(|x| x.kind()) == Some(SyntaxKind::TOKEN_WHITESPACE) {
+                first -= 1;
+            }
+            fragment("{", &format!("\n{}\n", render(c, "")), "}")
+        }
+        (None, _) => vec![],
+    };
+    let new = parent.green().splice_children(first..index, input);
+    Ok(SyntaxNode::new_root(parent.replace_with(new)))
+}
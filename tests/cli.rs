@@ -0,0 +1,274 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+};
+
+fn nixeditor(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nix-editor"))
+        .args(args)
+        .output()
+        .expect("Failed to run nix-editor")
+}
+
+// Copy a fixture into a directory of its own, so tests can edit it in place
+fn tmpcopy(test: &str, fixture: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nix-editor-{}-{}", test, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Failed to create directory");
+    let path = dir.join("configuration.nix");
+    fs::copy(Path::new("src/tests").join(fixture), &path).expect("Failed to copy file");
+    path
+}
+
+fn files(dir: &Path) -> Vec<String> {
+    let mut out = fs::read_dir(dir)
+        .expect("Failed to read directory")
+        .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    out.sort();
+    out
+}
+
+// The JSON error a failed command printed on stderr
+fn error(out: &Output) -> serde_json::Value {
+    assert!(!out.status.success());
+    let err: serde_json::Value = serde_json::from_slice(&out.stderr).expect("Failed to parse error");
+    err["error"].clone()
+}
+
+#[test]
+fn in_place1() {
+    let path = tmpcopy("in_place1", "configuration.nix");
+    let file = path.to_str().unwrap();
+    let original = fs::read_to_string(&path).unwrap();
+
+    let out = nixeditor(&[file, "set", "system.stateVersion", "\"23.05\"", "--in-place"]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    let edited = fs::read_to_string(&path).unwrap();
+    assert!(edited == original.replace("\"22.05\"", "\"23.05\""));
+    // No temporary or backup files are left behind
+    assert!(files(path.parent().unwrap()) == vec!["configuration.nix"]);
+
+    // A failed edit leaves the file alone
+    let out = nixeditor(&[file, "set", "system.stateVersion", "(", "--in-place"]);
+    assert!(!out.status.success());
+    assert!(fs::read_to_string(&path).unwrap() == edited);
+    assert!(files(path.parent().unwrap()) == vec!["configuration.nix"]);
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn in_place2() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = tmpcopy("in_place2", "configuration.nix");
+    let file = path.to_str().unwrap();
+
+    // The mode of the file is kept
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    let out = nixeditor(&[file, "set", "system.stateVersion", "\"23.05\"", "--in-place"]);
+    assert!(out.status.success());
+    assert!(fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o600);
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn backup1() {
+    let path = tmpcopy("backup1", "configuration.nix");
+    let file = path.to_str().unwrap();
+    let original = fs::read_to_string(&path).unwrap();
+    let dir = path.parent().unwrap();
+
+    let out = nixeditor(&[file, "set", "system.stateVersion", "\"23.05\"", "--in-place", "--backup"]);
+    assert!(out.status.success());
+    assert!(files(dir) == vec!["configuration.nix", "configuration.nix.bak"]);
+    assert!(fs::read_to_string(dir.join("configuration.nix.bak")).unwrap() == original);
+    assert!(fs::read_to_string(&path).unwrap().contains("system.stateVersion = \"23.05\";"));
+
+    let edited = fs::read_to_string(&path).unwrap();
+    let out = nixeditor(&[file, "unset", "system.stateVersion", "--in-place", "--backup=.orig"]);
+    assert!(out.status.success());
+    assert!(fs::read_to_string(dir.join("configuration.nix.orig")).unwrap() == edited);
+    assert!(!fs::read_to_string(&path).unwrap().contains("system.stateVersion"));
+
+    // A backup needs an edit in place
+    let out = nixeditor(&[file, "set", "system.stateVersion", "\"23.05\"", "--backup"]);
+    assert!(!out.status.success());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn imports1() {
+    // Imported files that can not be parsed are reported on stderr
+    let out = nixeditor(&["src/tests/skipped/configuration.nix", "get", "system.stateVersion", "--imports"]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout) == "\"22.05\"\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("skipped"));
+    assert!(stderr.contains("src/tests/skipped/broken.nix:7:1"));
+
+    let out = nixeditor(&["src/tests/skipped/configuration.nix", "get", "system.stateVersion", "--imports", "--json"]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let warning: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    assert!(warning["skipped"]["file"] == "src/tests/skipped/broken.nix");
+    assert!(warning["skipped"]["code"] == "parse_error");
+
+    // Edits to an imported file are only written in place
+    let args = ["src/tests/skipped/configuration.nix", "set", "users.users.alice.isNormalUser", "false", "--imports", "--json"];
+    let out = nixeditor(&args);
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let err: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert!(err["error"]["code"] == "imported_file");
+    assert!(err["error"]["message"].as_str().unwrap().contains("src/tests/skipped/users.nix"));
+    assert!(!out.status.success());
+}
+
+#[test]
+fn ensure1() {
+    let path = tmpcopy("ensure1", "configuration.nix");
+    let file = path.to_str().unwrap();
+    let dir = path.parent().unwrap();
+
+    // Nothing is reported as added when the output can not be written
+    let missing = dir.join("missing/out.nix");
+    let out = nixeditor(&[file, "list-add", "environment.systemPackages", "-a", "git", "--ensure", "-o", missing.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("added"));
+
+    let out = nixeditor(&[file, "list-add", "environment.systemPackages", "-a", "git", "-a", "wget", "--ensure", "--in-place", "--json"]);
+    assert!(out.status.success());
+    let added: serde_json::Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert!(added["added"] == serde_json::json!(["git"]));
+    assert!(fs::read_to_string(&path).unwrap().contains("git"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn json1() {
+    let out = nixeditor(&["src/tests/configuration.nix", "get", "system.stateVersion", "--json"]);
+    assert!(out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert!(value["attr"] == "system.stateVersion");
+    assert!(value["value"] == "\"22.05\"");
+    assert!(value["kind"] == "string");
+    assert!(value.get("span").is_none());
+    assert!(value["location"]["value"]["start"] == 3376);
+    assert!(value["location"]["value"]["end"] == 3383);
+    assert!(value["location"]["key"]["line"] == 102);
+    assert!(value["location"]["key"]["column"] == 3);
+    assert!(value["location"]["value"]["column"] == 25);
+    assert!(value["comment"]["trailing"] == "Did you read the comment?");
+    assert!(value["wrappers"] == serde_json::json!([]));
+
+    let out = nixeditor(&["src/tests/configuration.nix", "list", "environment.systemPackages", "--json"]);
+    assert!(out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert!(value == serde_json::json!([{ "elems": ["vim", "wget", "firefox"], "condition": null }]));
+
+    // Lists added under a condition keep it
+    let out = nixeditor(&["src/tests/concat.nix", "list", "environment.systemPackages", "--json"]);
+    assert!(out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert!(value == serde_json::json!([
+        { "elems": ["git", "htop"], "condition": null },
+        { "elems": ["firefox", "htop"], "condition": "cfg.gui" },
+    ]));
+
+    let out = nixeditor(&["src/tests/configuration.nix", "keys", "programs", "--json"]);
+    assert!(out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert!(value == serde_json::json!(["programs.gnupg.agent.enable", "programs.gnupg.agent.enableSSHSupport", "programs.mtr.enable"]));
+}
+
+#[test]
+fn json_error1() {
+    let file = "src/tests/configuration.nix";
+
+    let err = error(&nixeditor(&[file, "get", "services.openssh.ports", "--json"]));
+    assert!(err["code"] == "no_attr");
+    assert!(err["file"] == file);
+    assert!(err["attr"] == "services.openssh.ports");
+    assert!(err["prefix"] == "services");
+    assert!(err["missing"] == "openssh");
+    assert!(err["span"]["line"] == 38);
+
+    let err = error(&nixeditor(&[file, "get", "imports.x", "--json"]));
+    assert!(err["code"] == "not_attrset");
+    assert!(err["prefix"] == "imports");
+
+    let err = error(&nixeditor(&["src/tests/syntaxerror.nix", "get", "a", "--json"]));
+    assert!(err["code"] == "parse_error");
+    assert!(err["span"]["line"] == 5);
+    assert!(err["span"]["column"] == 17);
+
+    let err = error(&nixeditor(&["src/tests/missing.nix", "get", "a", "--json"]));
+    assert!(err["code"] == "no_file");
+    assert!(err["file"] == "src/tests/missing.nix");
+
+    let err = error(&nixeditor(&[file, "get", "system.stateVersion", "--in-place", "--json"]));
+    assert!(err["code"] == "read_in_place");
+
+    let err = error(&nixeditor(&[file, "set", "system.stateVersion", "(", "--json"]));
+    assert!(err["code"] == "invalid_value");
+    assert!(err["attr"] == "system.stateVersion");
+
+    let err = error(&nixeditor(&[file, "set", "boot", "1", "--json"]));
+    assert!(err["code"] == "write_value_to_set");
+
+    let err = error(&nixeditor(&[file, "list-add", "system.stateVersion", "-a", "git", "--json"]));
+    assert!(err["code"] == "array_error");
+
+    let err = error(&nixeditor(&[file, "list-remove", "environment.systemPackages", "-a", "git", "--strict", "--json"]));
+    assert!(err["code"] == "no_element");
+    assert!(err["span"]["line"] == 71);
+
+    let err = error(&nixeditor(&[file, "list-add", "environment.systemPackages", "-a", "git", "--index", "9", "--json"]));
+    assert!(err["code"] == "index_out_of_range");
+    assert!(err["label"] == "this list");
+
+    let err = error(&nixeditor(&[file, "set", "services.nope.enable", "true", "--options", "src/tests/options.json", "--json"]));
+    assert!(err["code"] == "unknown_option");
+
+    let err = error(&nixeditor(&[file, "list-add", "environment.systemPackages", "-a", "nopkg", "--packages", "src/tests/packages.json", "--json"]));
+    assert!(err["code"] == "unknown_package");
+
+    let err = error(&nixeditor(&[file, "set", "system.stateVersion", "\"23.05\"", "-o", "src/tests/missing/out.nix", "--json"]));
+    assert!(err["code"] == "io_error");
+    assert!(err["file"] == "src/tests/missing/out.nix");
+
+    // Without --json the error is printed for people, with the line it is about
+    let out = nixeditor(&[file, "get", "services.openssh.ports"]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("no attribute `services.openssh.ports`"));
+    assert!(stderr.contains("configuration.nix:38:3"));
+    let out = nixeditor(&["src/tests/missing.nix", "get", "a"]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("src/tests/missing.nix"));
+}
+
+#[test]
+fn remove1() {
+    let file = "src/tests/configuration.nix";
+
+    // Elements that are not in the list are reported
+    let out = nixeditor(&[file, "list-remove", "environment.systemPackages", "-a", "wget", "-a", "htop"]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("not found") && stderr.contains("htop") && !stderr.contains("wget"));
+
+    let out = nixeditor(&[file, "list-remove", "environment.systemPackages", "-a", "wget", "-a", "htop", "--json"]);
+    assert!(out.status.success());
+    let missing: serde_json::Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert!(missing["missing"] == serde_json::json!(["htop"]));
+
+    let out = nixeditor(&[file, "list-remove", "environment.systemPackages", "-a", "htop", "--imports", "--json"]);
+    assert!(out.status.success());
+    let missing: serde_json::Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert!(missing["missing"] == serde_json::json!(["htop"]));
+}